@@ -0,0 +1,269 @@
+// Copyright 2014 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Conversion of RGB samples to sRGB, from their primaries, white point and
+// transfer function (as given by cHRM and gAMA, or an ICC profile).
+
+use super::{ColorInfo, Chromaticities};
+use icc::{Profile, MatrixTrc, GrayTrc};
+
+pub type Matrix = [[f64, ..3], ..3];
+
+// CIE xy chromaticities of the sRGB primaries and white point (D65).
+static SRGB_PRIMARIES: [(f64, f64), ..3] = [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)];
+static D65: (f64, f64) = (0.3127, 0.3290);
+
+// Cone responses of the Bradford chromatic adaptation.
+static BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296]
+];
+
+// Number of steps of linear light encoded to sRGB after a matrix.
+static ENCODE_STEPS: uint = 16384;
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0, ..3], ..3];
+    for i in range(0u, 3) {
+        for j in range(0u, 3) {
+            for k in range(0u, 3) {
+                m[i][j] += a[i][k] * b[k][j];
+            }
+        }
+    }
+    m
+}
+
+fn mul_vec(a: &Matrix, v: [f64, ..3]) -> [f64, ..3] {
+    [a[0][0] * v[0] + a[0][1] * v[1] + a[0][2] * v[2],
+     a[1][0] * v[0] + a[1][1] * v[1] + a[1][2] * v[2],
+     a[2][0] * v[0] + a[2][1] * v[1] + a[2][2] * v[2]]
+}
+
+pub fn invert(m: &Matrix) -> Option<Matrix> {
+    let cofactor = |i: uint, j: uint| {
+        let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+        let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+        m[i1][j1] * m[i2][j2] - m[i1][j2] * m[i2][j1]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inverse = [[0.0, ..3], ..3];
+    for i in range(0u, 3) {
+        for j in range(0u, 3) {
+            inverse[i][j] = cofactor(j, i) / det;
+        }
+    }
+    Some(inverse)
+}
+
+fn is_identity(m: &Matrix) -> bool {
+    range(0u, 3).all(|i| range(0u, 3).all(|j| {
+        (m[i][j] - if i == j { 1.0 } else { 0.0 }).abs() < 1e-4
+    }))
+}
+
+// XYZ of a chromaticity, with Y = 1.
+pub fn xyz((x, y): (f64, f64)) -> [f64, ..3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+// The RGB to XYZ matrix for the given primaries (red, green and blue) and white point.
+pub fn rgb_to_xyz(primaries: [(f64, f64), ..3], white: (f64, f64)) -> Option<Matrix> {
+    let (_, white_y) = white;
+    if white_y <= 0.0 || primaries.iter().any(|&(_, y)| y <= 0.0) {
+        return None;
+    }
+    let (r, g, b) = (xyz(primaries[0]), xyz(primaries[1]), xyz(primaries[2]));
+    let mut m = [[r[0], g[0], b[0]],
+                 [r[1], g[1], b[1]],
+                 [r[2], g[2], b[2]]];
+    // Scale each primary so that they add up to the white point.
+    let scale = match invert(&m) {
+        Some(inverse) => mul_vec(&inverse, xyz(white)),
+        None => return None
+    };
+    for i in range(0u, 3) {
+        for j in range(0u, 3) {
+            m[i][j] *= scale[j];
+        }
+    }
+    Some(m)
+}
+
+// Bradford adaptation of XYZ colors between two white points (as XYZ).
+fn bradford(from: [f64, ..3], to: [f64, ..3]) -> Matrix {
+    let (a, b) = (mul_vec(&BRADFORD, from), mul_vec(&BRADFORD, to));
+    let scale = [[b[0] / a[0], 0.0, 0.0],
+                 [0.0, b[1] / a[1], 0.0],
+                 [0.0, 0.0, b[2] / a[2]]];
+    mul(&invert(&BRADFORD).unwrap(), &mul(&scale, &BRADFORD))
+}
+
+// Linear RGB to linear sRGB, from the RGB to XYZ matrix and its white point
+// (as XYZ), adapted to D65. None if it's close enough to do nothing, or if
+// the white point made it infinite or NaN.
+pub fn to_srgb_matrix(rgb_to_xyz_matrix: &Matrix, white: [f64, ..3]) -> Option<Matrix> {
+    let srgb_to_xyz = rgb_to_xyz(SRGB_PRIMARIES, D65).unwrap();
+    let xyz_to_srgb = invert(&srgb_to_xyz).unwrap();
+    let m = mul(&xyz_to_srgb, &mul(&bradford(white, xyz(D65)), rgb_to_xyz_matrix));
+    let finite = m.iter().all(|row| row.iter().all(|v| v.is_finite()));
+    if !finite || is_identity(&m) { None } else { Some(m) }
+}
+
+pub fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(l: f64) -> f64 {
+    if l <= 0.0031308 {
+        l * 12.92
+    } else {
+        1.055 * l.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// NaN becomes 0.
+fn clamp(v: f64) -> f64 {
+    if !(v > 0.0) { 0.0 } else if v > 1.0 { 1.0 } else { v }
+}
+
+fn to_u8(v: f64) -> u8 {
+    (clamp(v) * 255.0 + 0.5) as u8
+}
+
+// Converts RGB samples (8-bit or 16-bit) to 8-bit sRGB.
+pub enum SrgbTransform {
+    // Per channel, samples straight to sRGB, for sRGB primaries and white point.
+    SrgbDirect(Vec<Vec<u8>>),
+    // Per channel, samples to linear light, then the matrix to linear sRGB
+    // and linear light (in ENCODE_STEPS) to sRGB.
+    SrgbMatrix(Vec<Vec<f32>>, [[f32, ..3], ..3], Vec<u8>)
+}
+
+impl SrgbTransform {
+    // `to_linear` maps each channel's samples (from 0 to 1) to linear light.
+    pub fn new(matrix: Option<Matrix>, sixteen: bool, to_linear: |uint, f64| -> f64) -> SrgbTransform {
+        let max = if sixteen { 65535u } else { 255u };
+        match matrix {
+            None => {
+                let mut tables = Vec::with_capacity(3);
+                for c in range(0u, 3) {
+                    let mut table = Vec::with_capacity(max + 1);
+                    for v in range(0, max + 1) {
+                        table.push(to_u8(linear_to_srgb(clamp(to_linear(c, v as f64 / max as f64)))));
+                    }
+                    tables.push(table);
+                }
+                SrgbDirect(tables)
+            }
+            Some(m) => {
+                let mut tables = Vec::with_capacity(3);
+                for c in range(0u, 3) {
+                    let mut table = Vec::with_capacity(max + 1);
+                    for v in range(0, max + 1) {
+                        table.push(to_linear(c, v as f64 / max as f64) as f32);
+                    }
+                    tables.push(table);
+                }
+                let mut matrix = [[0.0f32, ..3], ..3];
+                for i in range(0u, 3) {
+                    for j in range(0u, 3) {
+                        matrix[i][j] = m[i][j] as f32;
+                    }
+                }
+                let encode = Vec::from_fn(ENCODE_STEPS, |i| {
+                    to_u8(linear_to_srgb(i as f64 / (ENCODE_STEPS - 1) as f64))
+                });
+                SrgbMatrix(tables, matrix, encode)
+            }
+        }
+    }
+
+    // From the colorimetry of a PNG image, where sRGB overrides iCCP, which
+    // overrides cHRM and gAMA, and anything missing is taken to be sRGB.
+    pub fn from_png(color: &ColorInfo, gamma: Option<u32>, sixteen: bool, gray: bool) -> SrgbTransform {
+        match color.icc_profile {
+            Some(ref icc) if color.srgb_intent.is_none() => {
+                match Profile::parse(icc.data.as_slice()) {
+                    Ok(profile) => {
+                        let matches = match profile {
+                            MatrixTrc(..) => !gray,
+                            GrayTrc(_) => gray
+                        };
+                        if matches {
+                            return profile.to_srgb(sixteen);
+                        }
+                        warn!("ignoring ICC profile {}, its color space doesn't match the image", icc.name);
+                    }
+                    Err(e) => warn!("ignoring ICC profile {}: {}", icc.name, e)
+                }
+            }
+            _ => {}
+        }
+        let (chromaticities, gamma) = match color.srgb_intent {
+            Some(_) => (None, None),
+            None => (color.chromaticities.as_ref(), gamma)
+        };
+        let matrix = match chromaticities {
+            Some(c) => match chromaticities_to_xyz(c) {
+                Some(m) => to_srgb_matrix(&m, xyz(chromaticity(c.white))),
+                None => {
+                    warn!("ignoring invalid cHRM chromaticities {}", c);
+                    None
+                }
+            },
+            None => None
+        };
+        SrgbTransform::new(matrix, sixteen, |_, v| {
+            match gamma {
+                Some(g) => v.powf(100000.0 / g as f64),
+                None => srgb_to_linear(v)
+            }
+        })
+    }
+
+    pub fn convert(&self, rgb: [u16, ..3]) -> [u16, ..3] {
+        match *self {
+            SrgbDirect(ref tables) => {
+                let sample = |c: uint| tables.get(c).as_slice()[rgb[c] as uint] as u16;
+                [sample(0), sample(1), sample(2)]
+            }
+            SrgbMatrix(ref tables, ref m, ref encode) => {
+                let l = [tables.get(0).as_slice()[rgb[0] as uint],
+                         tables.get(1).as_slice()[rgb[1] as uint],
+                         tables.get(2).as_slice()[rgb[2] as uint]];
+                let mut out = [0u16, ..3];
+                for i in range(0u, 3) {
+                    let v = m[i][0] * l[0] + m[i][1] * l[1] + m[i][2] * l[2];
+                    // Written so that NaN, which isn't a valid index either, becomes 0.
+                    let v = if !(v > 0.0) { 0.0 } else if v > 1.0 { 1.0 } else { v };
+                    out[i] = encode.as_slice()[(v * (ENCODE_STEPS - 1) as f32 + 0.5) as uint] as u16;
+                }
+                out
+            }
+        }
+    }
+}
+
+// cHRM values are times 100000.
+fn chromaticity((x, y): (u32, u32)) -> (f64, f64) {
+    (x as f64 / 100000.0, y as f64 / 100000.0)
+}
+
+fn chromaticities_to_xyz(c: &Chromaticities) -> Option<Matrix> {
+    rgb_to_xyz([chromaticity(c.red), chromaticity(c.green), chromaticity(c.blue)], chromaticity(c.white))
+}
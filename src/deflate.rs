@@ -0,0 +1,817 @@
+// Copyright 2014 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![allow(missing_doc)]
+
+use std::cmp;
+use std::mem;
+
+use checksum::Adler32;
+
+static WINDOW_SIZE: uint = 32 * 1024;
+static MIN_MATCH: uint = 3;
+static MAX_MATCH: uint = 258;
+
+// Matches of length 3 further back than this are usually worse than literals.
+static TOO_FAR: uint = 4096;
+
+static HASH_SIZE: uint = 1 << 15;
+
+// Number of literals and matches buffered before a block is emitted.
+static BLOCK_SYMBOLS: uint = 16 * 1024;
+
+static STORED_BLOCK_SIZE: uint = 0xffff;
+
+// (max_lazy, nice_length, max_chain) for each level, like zlib's
+// configuration_table, levels 1 to 3 don't use lazy matching.
+static LEVELS: [(uint, uint, uint), ..10] = [
+    (0, 0, 0),
+    (0, 8, 4),
+    (0, 16, 8),
+    (0, 32, 32),
+    (4, 16, 16),
+    (16, 32, 32),
+    (16, 128, 128),
+    (32, 128, 256),
+    (128, 258, 1024),
+    (258, 258, 4096)
+];
+
+static LENGTH_BASE: [u16, ..29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+
+static LENGTH_EXTRA: [u8, ..29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+
+static DIST_BASE: [u16, ..30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+
+static DIST_EXTRA: [u8, ..30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+static CLEN_ORDER: [uint, ..19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Index of the last entry in a sorted base table that is <= value.
+fn base_index(bases: &[u16], value: uint) -> uint {
+    let mut i = bases.len() - 1;
+    while bases[i] as uint > value {
+        i -= 1;
+    }
+    i
+}
+
+fn reverse_bits(mut code: u16, bits: u8) -> u16 {
+    let mut rev = 0;
+    for _ in range(0, bits) {
+        rev = (rev << 1) | (code & 1);
+        code >>= 1;
+    }
+    rev
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    v: u32,
+    n: u8
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            out: Vec::new(),
+            v: 0,
+            n: 0
+        }
+    }
+
+    fn write(&mut self, v: u16, n: u8) {
+        self.v |= v as u32 << self.n;
+        self.n += n;
+        while self.n >= 8 {
+            self.out.push(self.v as u8);
+            self.v >>= 8;
+            self.n -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.n > 0 {
+            self.out.push(self.v as u8);
+            self.v = 0;
+            self.n = 0;
+        }
+    }
+}
+
+// Compute Huffman code lengths for the given symbol frequencies,
+// without any limit on the length of the codes.
+fn huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut lengths = Vec::from_elem(freqs.len(), 0u8);
+    let mut leaves = Vec::new();
+    for (i, &f) in freqs.iter().enumerate() {
+        if f > 0 {
+            leaves.push((f, i));
+        }
+    }
+    leaves.as_mut_slice().sort();
+
+    let n = leaves.len();
+    if n < 2 {
+        for &(_, i) in leaves.iter() {
+            *lengths.get_mut(i) = 1;
+        }
+        return lengths;
+    }
+
+    // Leaves are the nodes 0..n and internal nodes are added after them,
+    // in increasing order of weight, so two queues are enough to always
+    // pick the two lightest nodes.
+    let mut weights: Vec<u32> = leaves.iter().map(|&(f, _)| f).collect();
+    let mut parents = Vec::from_elem(2 * n - 1, 0u);
+    let (mut next_leaf, mut next_node) = (0u, n);
+    for node in range(n, 2 * n - 1) {
+        let mut children = [0u, ..2];
+        for child in children.mut_iter() {
+            *child = if next_leaf < n && (next_node >= node ||
+                                          *weights.get(next_leaf) <= *weights.get(next_node)) {
+                next_leaf += 1;
+                next_leaf - 1
+            } else {
+                next_node += 1;
+                next_node - 1
+            };
+        }
+        let weight = *weights.get(children[0]) + *weights.get(children[1]);
+        weights.push(weight);
+        *parents.get_mut(children[0]) = node;
+        *parents.get_mut(children[1]) = node;
+    }
+
+    // Parents always come after their children, the root is the last node.
+    let mut depths = Vec::from_elem(2 * n - 1, 0u);
+    for node in range(0, 2 * n - 2).rev() {
+        let depth = *depths.get(*parents.get(node)) + 1;
+        *depths.get_mut(node) = depth;
+    }
+    for (leaf, &(_, i)) in leaves.iter().enumerate() {
+        *lengths.get_mut(i) = cmp::min(*depths.get(leaf), 0xff) as u8;
+    }
+    lengths
+}
+
+struct Huffman {
+    // Codes are stored bit-reversed, ready to be written LSB first.
+    codes: Vec<u16>,
+    lengths: Vec<u8>
+}
+
+impl Huffman {
+    fn from_lengths(lengths: Vec<u8>) -> Huffman {
+        let mut bl_count = [0u16, ..16];
+        for &bits in lengths.iter() {
+            if bits != 0 {
+                bl_count[bits as uint] += 1;
+            }
+        }
+
+        let mut next_code = [0u16, ..16];
+        for bits in range(1u, 16) {
+            next_code[bits] = (next_code[bits - 1] + bl_count[bits - 1]) << 1;
+        }
+
+        let mut codes = Vec::with_capacity(lengths.len());
+        for &bits in lengths.iter() {
+            if bits == 0 {
+                codes.push(0);
+            } else {
+                codes.push(reverse_bits(next_code[bits as uint], bits));
+                next_code[bits as uint] += 1;
+            }
+        }
+
+        Huffman {
+            codes: codes,
+            lengths: lengths
+        }
+    }
+
+    fn from_freqs(freqs: &[u32], max_bits: u8) -> Huffman {
+        let mut freqs = Vec::from_slice(freqs);
+
+        // Always use at least two codes, a single code of length 1
+        // is an incomplete code that some decoders reject.
+        let mut used = freqs.iter().filter(|&&f| f > 0).count();
+        for i in range(0u, 2) {
+            if used < 2 && *freqs.get(i) == 0 {
+                *freqs.get_mut(i) = 1;
+                used += 1;
+            }
+        }
+
+        // Flatten the distribution until the longest code fits.
+        loop {
+            let lengths = huffman_lengths(freqs.as_slice());
+            if lengths.iter().all(|&bits| bits <= max_bits) {
+                return Huffman::from_lengths(lengths);
+            }
+            for f in freqs.mut_iter() {
+                if *f > 1 {
+                    *f = (*f + 1) / 2;
+                }
+            }
+        }
+    }
+
+    fn fixed_lit() -> Huffman {
+        let mut lengths = Vec::with_capacity(288);
+        lengths.grow(144, &8u8);
+        lengths.grow(112, &9u8);
+        lengths.grow(24, &7u8);
+        lengths.grow(8, &8u8);
+        Huffman::from_lengths(lengths)
+    }
+
+    fn fixed_dist() -> Huffman {
+        Huffman::from_lengths(Vec::from_elem(30, 5u8))
+    }
+
+    fn cost(&self, freqs: &[u32]) -> uint {
+        freqs.iter().zip(self.lengths.iter()).fold(0, |cost, (&f, &bits)| cost + f as uint * bits as uint)
+    }
+
+    fn write(&self, w: &mut BitWriter, symbol: uint) {
+        w.write(*self.codes.get(symbol), *self.lengths.get(symbol));
+    }
+}
+
+// Code lengths of a dynamic block, along with the code used to send them.
+struct DynamicHeader {
+    lit: Huffman,
+    dist: Huffman,
+    clen: Huffman,
+    hlit: uint,
+    hdist: uint,
+    hclen: uint,
+    // Code length symbols (0-18) and the value of their extra bits.
+    clens: Vec<(u8, u8)>
+}
+
+impl DynamicHeader {
+    fn new(lit_freqs: &[u32], dist_freqs: &[u32]) -> DynamicHeader {
+        let lit = Huffman::from_freqs(lit_freqs, 15);
+        let dist = Huffman::from_freqs(dist_freqs, 15);
+
+        let mut hlit = lit.lengths.len();
+        while hlit > 257 && *lit.lengths.get(hlit - 1) == 0 {
+            hlit -= 1;
+        }
+        let mut hdist = dist.lengths.len();
+        while hdist > 1 && *dist.lengths.get(hdist - 1) == 0 {
+            hdist -= 1;
+        }
+
+        // Run-length encode both sets of lengths together, repeats may cross over.
+        let mut lengths = Vec::from_slice(lit.lengths.slice_to(hlit));
+        lengths.push_all(dist.lengths.slice_to(hdist));
+        let lengths = lengths.as_slice();
+
+        let mut clens = Vec::new();
+        let mut i = 0;
+        while i < lengths.len() {
+            let bits = lengths[i];
+            let mut run = 1;
+            while i + run < lengths.len() && lengths[i + run] == bits {
+                run += 1;
+            }
+            if bits == 0 && run >= 3 {
+                let run = cmp::min(run, 138);
+                if run >= 11 {
+                    clens.push((18, (run - 11) as u8));
+                } else {
+                    clens.push((17, (run - 3) as u8));
+                }
+                i += run;
+            } else if bits != 0 && run >= 4 {
+                clens.push((bits, 0));
+                i += 1;
+                let mut left = run - 1;
+                while left >= 3 {
+                    let repeat = cmp::min(left, 6);
+                    clens.push((16, (repeat - 3) as u8));
+                    left -= repeat;
+                    i += repeat;
+                }
+            } else {
+                clens.push((bits, 0));
+                i += 1;
+            }
+        }
+
+        let mut clen_freqs = [0u32, ..19];
+        for &(symbol, _) in clens.iter() {
+            clen_freqs[symbol as uint] += 1;
+        }
+        let clen = Huffman::from_freqs(clen_freqs, 7);
+
+        let mut hclen = 19;
+        while hclen > 4 && *clen.lengths.get(CLEN_ORDER[hclen - 1]) == 0 {
+            hclen -= 1;
+        }
+
+        DynamicHeader {
+            lit: lit,
+            dist: dist,
+            clen: clen,
+            hlit: hlit,
+            hdist: hdist,
+            hclen: hclen,
+            clens: clens
+        }
+    }
+
+    fn cost(&self) -> uint {
+        let mut bits = 5 + 5 + 4 + 3 * self.hclen;
+        for &(symbol, _) in self.clens.iter() {
+            bits += *self.clen.lengths.get(symbol as uint) as uint + match symbol {
+                16 => 2,
+                17 => 3,
+                18 => 7,
+                _ => 0
+            };
+        }
+        bits
+    }
+
+    fn write(&self, w: &mut BitWriter) {
+        w.write((self.hlit - 257) as u16, 5);
+        w.write((self.hdist - 1) as u16, 5);
+        w.write((self.hclen - 4) as u16, 4);
+        for &i in CLEN_ORDER.slice_to(self.hclen).iter() {
+            w.write(*self.clen.lengths.get(i) as u16, 3);
+        }
+        for &(symbol, extra) in self.clens.iter() {
+            self.clen.write(w, symbol as uint);
+            match symbol {
+                16 => w.write(extra as u16, 2),
+                17 => w.write(extra as u16, 3),
+                18 => w.write(extra as u16, 7),
+                _ => {}
+            }
+        }
+    }
+}
+
+enum Symbol {
+    Literal(u8),
+    Match(/* len */ u16, /* dist */ u16)
+}
+
+fn write_symbols(w: &mut BitWriter, symbols: &[Symbol], lit: &Huffman, dist: &Huffman) {
+    for &symbol in symbols.iter() {
+        match symbol {
+            Literal(b) => lit.write(w, b as uint),
+            Match(len, d) => {
+                let code = base_index(LENGTH_BASE, len as uint);
+                lit.write(w, 257 + code);
+                if LENGTH_EXTRA[code] > 0 {
+                    w.write(len - LENGTH_BASE[code], LENGTH_EXTRA[code]);
+                }
+                let code = base_index(DIST_BASE, d as uint);
+                dist.write(w, code);
+                if DIST_EXTRA[code] > 0 {
+                    w.write(d - DIST_BASE[code], DIST_EXTRA[code]);
+                }
+            }
+        }
+    }
+    lit.write(w, 256);
+}
+
+fn write_stored(w: &mut BitWriter, data: &[u8], final: bool) {
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.is_empty() {
+        w.write(final as u16, 3);
+        w.align();
+        w.write(0x0000, 16);
+        w.write(0xffff, 16);
+    }
+    loop {
+        let block = match blocks.next() {
+            Some(block) => block,
+            None => break
+        };
+        w.write((final && blocks.is_empty()) as u16, 3);
+        w.align();
+        w.write(block.len() as u16, 16);
+        w.write(!(block.len() as u16), 16);
+        w.out.push_all(block);
+    }
+}
+
+pub struct DeflateStream {
+    zlib: bool,
+    adler: Adler32,
+    header_written: bool,
+    finished: bool,
+    level: u8,
+    max_lazy: uint,
+    nice_length: uint,
+    max_chain: uint,
+
+    // Input bytes, window[0] is at the absolute position base.
+    window: Vec<u8>,
+    base: uint,
+    pos: uint,
+
+    // Hash chains, with absolute positions stored as pos + 1 (0 means empty).
+    head: Vec<uint>,
+    prev: Vec<uint>,
+
+    // Lazy matching state: a match at pos - 1 waiting to be compared.
+    match_available: bool,
+    prev_len: uint,
+    prev_dist: uint,
+
+    symbols: Vec<Symbol>,
+    block_start: uint,
+    block_len: uint,
+
+    writer: BitWriter
+}
+
+impl DeflateStream {
+    // Raw DEFLATE stream, with a compression level between 0 (no compression) and 9.
+    pub fn new(level: u8) -> DeflateStream {
+        let level = cmp::min(level, 9);
+        let (max_lazy, nice_length, max_chain) = LEVELS[level as uint];
+        DeflateStream {
+            zlib: false,
+            adler: Adler32::new(),
+            header_written: false,
+            finished: false,
+            level: level,
+            max_lazy: max_lazy,
+            nice_length: nice_length,
+            max_chain: max_chain,
+            window: Vec::with_capacity(3 * WINDOW_SIZE),
+            base: 0,
+            pos: 0,
+            head: Vec::from_elem(HASH_SIZE, 0u),
+            prev: Vec::from_elem(WINDOW_SIZE, 0u),
+            match_available: false,
+            prev_len: 0,
+            prev_dist: 0,
+            symbols: Vec::with_capacity(BLOCK_SYMBOLS),
+            block_start: 0,
+            block_len: 0,
+            writer: BitWriter::new()
+        }
+    }
+
+    pub fn to_zlib(level: u8) -> DeflateStream {
+        let mut stream = DeflateStream::new(level);
+        stream.zlib = true;
+        stream
+    }
+
+    fn write_header(&mut self) {
+        if !self.zlib || self.header_written {
+            return;
+        }
+        self.header_written = true;
+
+        // CM=8 (DEFLATE) and CINFO=7 (32K window).
+        let cmf = 0x78u8;
+        let level = match self.level {
+            0 | 1 => 0,
+            2..5 => 1,
+            6 => 2,
+            _ => 3
+        };
+        let flg = level << 6;
+        let check = 31 - ((cmf as u16 << 8) | flg as u16) % 31;
+        self.writer.out.push(cmf);
+        self.writer.out.push(flg | (check % 31) as u8);
+    }
+
+    // Insert pos into the hash chains, returning the previous chain head.
+    fn insert(&mut self, pos: uint) -> uint {
+        let h = {
+            let w = self.window.slice_from(pos - self.base);
+            ((w[0] as uint << 10) ^ (w[1] as uint << 5) ^ w[2] as uint) & (HASH_SIZE - 1)
+        };
+        let head = *self.head.get(h);
+        *self.prev.get_mut(pos & (WINDOW_SIZE - 1)) = head;
+        *self.head.get_mut(h) = pos + 1;
+        head
+    }
+
+    // Find the longest match for pos that is longer than prev_len,
+    // following the hash chain starting with the position candidate.
+    fn longest_match(&self, mut candidate: uint, max_len: uint) -> (uint, uint) {
+        let (mut best_len, mut best_dist) = (cmp::max(self.prev_len, MIN_MATCH - 1), 0);
+        if best_len >= max_len {
+            return (0, 0);
+        }
+
+        let pos = self.pos;
+        let limit = cmp::max(self.base, if pos > WINDOW_SIZE { pos - WINDOW_SIZE } else { 0 });
+        let current = self.window.slice(pos - self.base, pos - self.base + max_len);
+        let mut chain = self.max_chain;
+        while candidate >= limit {
+            let c = self.window.slice_from(candidate - self.base);
+            if c[best_len] == current[best_len] && c[0] == current[0] {
+                let mut len = 1;
+                while len < max_len && c[len] == current[len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len >= self.nice_length || len == max_len {
+                        break;
+                    }
+                }
+            }
+
+            chain -= 1;
+            let next = *self.prev.get(candidate & (WINDOW_SIZE - 1));
+            if chain == 0 || next == 0 || next - 1 >= candidate {
+                break;
+            }
+            candidate = next - 1;
+        }
+
+        if best_dist == 0 {
+            (0, 0)
+        } else {
+            (best_len, best_dist)
+        }
+    }
+
+    fn push_literal(&mut self, pos: uint) {
+        let b = self.window.as_slice()[pos - self.base];
+        self.symbols.push(Literal(b));
+        self.block_len += 1;
+    }
+
+    fn push_match(&mut self, len: uint, dist: uint) {
+        self.symbols.push(Match(len as u16, dist as u16));
+        self.block_len += len;
+    }
+
+    // Drop the oldest WINDOW_SIZE bytes, which are out of reach for matches.
+    fn slide(&mut self) {
+        if self.block_start < self.base + WINDOW_SIZE {
+            self.flush_block(false);
+        }
+        self.window = Vec::from_slice(self.window.slice_from(WINDOW_SIZE));
+        self.base += WINDOW_SIZE;
+    }
+
+    fn deflate(&mut self, finish: bool) {
+        let lazy = self.level >= 4;
+        loop {
+            if self.pos - self.base >= 2 * WINDOW_SIZE {
+                self.slide();
+            }
+
+            let end = self.base + self.window.len();
+            let lookahead = end - self.pos;
+            if lookahead == 0 || (!finish && lookahead < MAX_MATCH) {
+                break;
+            }
+
+            if self.level == 0 {
+                let n = cmp::min(lookahead, STORED_BLOCK_SIZE - self.block_len);
+                self.pos += n;
+                self.block_len += n;
+                if self.block_len >= STORED_BLOCK_SIZE {
+                    self.flush_block(false);
+                }
+                continue;
+            }
+
+            let (mut len, mut dist) = (0, 0);
+            if lookahead >= MIN_MATCH {
+                let pos = self.pos;
+                let head = self.insert(pos);
+                if head != 0 && (!lazy || self.prev_len < self.max_lazy) {
+                    let (l, d) = self.longest_match(head - 1, cmp::min(lookahead, MAX_MATCH));
+                    if l > MIN_MATCH || (l == MIN_MATCH && d <= TOO_FAR) {
+                        len = l;
+                        dist = d;
+                    }
+                }
+            }
+
+            if !lazy {
+                let pos = self.pos;
+                if len >= MIN_MATCH {
+                    self.push_match(len, dist);
+                    for p in range(pos + 1, pos + len) {
+                        if p + MIN_MATCH <= end {
+                            self.insert(p);
+                        }
+                    }
+                    self.pos += len;
+                } else {
+                    self.push_literal(pos);
+                    self.pos += 1;
+                }
+            } else if self.prev_len >= MIN_MATCH && len <= self.prev_len {
+                // The match starting at the previous position is at least as good.
+                let (prev_len, prev_dist) = (self.prev_len, self.prev_dist);
+                self.push_match(prev_len, prev_dist);
+                let start = self.pos - 1;
+                for p in range(self.pos + 1, start + prev_len) {
+                    if p + MIN_MATCH <= end {
+                        self.insert(p);
+                    }
+                }
+                self.pos = start + prev_len;
+                self.match_available = false;
+                self.prev_len = 0;
+            } else {
+                if self.match_available {
+                    let prev = self.pos - 1;
+                    self.push_literal(prev);
+                }
+                self.match_available = true;
+                self.prev_len = len;
+                self.prev_dist = dist;
+                self.pos += 1;
+            }
+
+            if self.symbols.len() >= BLOCK_SYMBOLS {
+                self.flush_block(false);
+            }
+        }
+
+        if finish && self.match_available {
+            let prev = self.pos - 1;
+            self.push_literal(prev);
+            self.match_available = false;
+        }
+    }
+
+    // Emit the buffered symbols as whichever block type is the smallest.
+    fn flush_block(&mut self, final: bool) {
+        let symbols = mem::replace(&mut self.symbols, Vec::with_capacity(BLOCK_SYMBOLS));
+
+        let mut lit_freqs = [0u32, ..286];
+        let mut dist_freqs = [0u32, ..30];
+        let mut extra_bits = 0u;
+        for &symbol in symbols.iter() {
+            match symbol {
+                Literal(b) => lit_freqs[b as uint] += 1,
+                Match(len, dist) => {
+                    let code = base_index(LENGTH_BASE, len as uint);
+                    lit_freqs[257 + code] += 1;
+                    extra_bits += LENGTH_EXTRA[code] as uint;
+                    let code = base_index(DIST_BASE, dist as uint);
+                    dist_freqs[code] += 1;
+                    extra_bits += DIST_EXTRA[code] as uint;
+                }
+            }
+        }
+        lit_freqs[256] = 1;
+
+        let start = self.block_start - self.base;
+        let raw = self.window.slice(start, start + self.block_len);
+        let stored_blocks = cmp::max(1, (raw.len() + STORED_BLOCK_SIZE - 1) / STORED_BLOCK_SIZE);
+        let stored_cost = stored_blocks * (3 + 7 + 32) + raw.len() * 8;
+
+        let w = &mut self.writer;
+        if self.level == 0 {
+            write_stored(w, raw, final);
+        } else {
+            let (fixed_lit, fixed_dist) = (Huffman::fixed_lit(), Huffman::fixed_dist());
+            let fixed_cost = 3 + fixed_lit.cost(lit_freqs) + fixed_dist.cost(dist_freqs) + extra_bits;
+
+            let header = DynamicHeader::new(lit_freqs, dist_freqs);
+            let dynamic_cost = 3 + header.cost() + header.lit.cost(lit_freqs)
+                                 + header.dist.cost(dist_freqs) + extra_bits;
+
+            if stored_cost <= fixed_cost && stored_cost <= dynamic_cost {
+                write_stored(w, raw, final);
+            } else if fixed_cost <= dynamic_cost {
+                w.write(final as u16 | (1 << 1), 3);
+                write_symbols(w, symbols.as_slice(), &fixed_lit, &fixed_dist);
+            } else {
+                w.write(final as u16 | (2 << 1), 3);
+                header.write(w);
+                write_symbols(w, symbols.as_slice(), &header.lit, &header.dist);
+            }
+        }
+
+        self.block_start += self.block_len;
+        self.block_len = 0;
+    }
+
+    // Compress more input, returning the compressed data produced so far.
+    // The returned output is only valid until the next call.
+    pub fn update<'a>(&'a mut self, data: &[u8]) -> &'a [u8] {
+        assert!(!self.finished);
+        self.writer.out.clear();
+        self.write_header();
+        if self.zlib {
+            self.adler.update(data);
+        }
+        // Feeding the input in pieces keeps the window from growing.
+        for piece in data.chunks(WINDOW_SIZE) {
+            self.window.push_all(piece);
+            self.deflate(false);
+        }
+        self.writer.out.as_slice()
+    }
+
+    // Compress any remaining input and end the stream (with the
+    // Adler-32 trailer for ZLIB streams), returning the last output.
+    pub fn finish<'a>(&'a mut self) -> &'a [u8] {
+        self.writer.out.clear();
+        if !self.finished {
+            self.finished = true;
+            self.write_header();
+            self.deflate(true);
+            self.flush_block(true);
+            self.writer.align();
+            if self.zlib {
+                let adler = self.adler.checksum();
+                let out = &mut self.writer.out;
+                out.push((adler >> 24) as u8);
+                out.push((adler >> 16) as u8);
+                out.push((adler >> 8) as u8);
+                out.push(adler as u8);
+            }
+        }
+        self.writer.out.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use inflate::InflateStream;
+    use super::DeflateStream;
+
+    fn inflate(mut stream: InflateStream, mut data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while data.len() > 0 {
+            let (used, output) = stream.update(data).unwrap();
+            out.push_all(output);
+            data = data.slice_from(used);
+        }
+        out
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let mut input = Vec::new();
+        for i in range(0u, 100000) {
+            input.push((i * i / 7 % 251) as u8);
+            if i % 3 == 0 {
+                input.push_all("rust-png".as_bytes());
+            }
+        }
+        for level in range(0u8, 10) {
+            for &zlib in [true, false].iter() {
+                let mut stream = if zlib {
+                    DeflateStream::to_zlib(level)
+                } else {
+                    DeflateStream::new(level)
+                };
+                let mut compressed = Vec::new();
+                for chunk in input.as_slice().chunks(10000) {
+                    compressed.push_all(stream.update(chunk));
+                }
+                compressed.push_all(stream.finish());
+                // Level 0 only has stored blocks, each with a 5 byte header.
+                if level == 0 {
+                    assert!(compressed.len() > input.len());
+                } else {
+                    assert!(compressed.len() < input.len());
+                }
+                let inflater = if zlib {
+                    InflateStream::from_zlib()
+                } else {
+                    InflateStream::new()
+                };
+                assert_eq!(inflate(inflater, compressed.as_slice()), input);
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2014 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::File;
+
+use checksum::Crc32;
+use deflate::DeflateStream;
+use super::{Image, MAGIC, PngError, Io, Malformed, Unsupported};
+use super::{K1, K2, K4, K8, K16, KA8, KA16, Pal1, Pal2, Pal4, Pal8, RGB8, RGB16, RGBA8, RGBA16};
+use super::{LayoutBGRA8, LayoutARGB8};
+
+// Maximum amount of compressed data per IDAT chunk.
+static IDAT_SIZE: uint = 8192;
+
+// Compression level used for IDAT data.
+static COMPRESSION_LEVEL: u8 = 6;
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.push((v >> 24) as u8);
+    out.push((v >> 16) as u8);
+    out.push((v >> 8) as u8);
+    out.push(v as u8);
+}
+
+fn write_chunk(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    push_u32(out, data.len() as u32);
+    let mut crc = Crc32::new();
+    crc.update(name.as_bytes());
+    crc.update(data);
+    out.push_all(name.as_bytes());
+    out.push_all(data);
+    push_u32(out, crc.checksum());
+}
+
+pub fn encode_png_to_memory(image: &Image) -> Result<Vec<u8>, PngError> {
+    let (bits, color_type) = match image.color_type {
+        K1 => (1, 0),
+        K2 => (2, 0),
+        K4 => (4, 0),
+        K8 => (8, 0),
+        K16 => (16, 0),
+        RGB8 => (8, 2),
+        RGB16 => (16, 2),
+        KA8 => (8, 4),
+        KA16 => (16, 4),
+        RGBA8 => (8, 6),
+        RGBA16 => (16, 6),
+        Pal1 => (1, 3),
+        Pal2 => (2, 3),
+        Pal4 => (4, 3),
+        Pal8 => (8, 3)
+    };
+
+    if image.premultiplied {
+        return Err(Unsupported("can't encode premultiplied alpha, see Image::unpremultiply".to_string()));
+    }
+
+    let palette = match image.palette {
+        Some(ref palette) if color_type == 3 => Some(palette),
+        None if color_type == 3 => return Err(Malformed("can't encode palette images without a palette".to_string())),
+        _ => None
+    };
+
+    let w = image.width as uint;
+    let h = image.height as uint;
+    if w == 0 || h == 0 {
+        return Err(Malformed(format!("can't encode an empty {}x{} image", w, h)));
+    }
+
+    let scanline_bytes = (w * image.color_type.pixel_bits() + 7) / 8;
+    if image.pixels.len() != scanline_bytes * h {
+        return Err(Malformed(format!("expected {} bytes of pixels for a {}x{} image but found {}",
+                                     scanline_bytes * h, w, h, image.pixels.len())));
+    }
+
+    let mut png = Vec::from_slice(MAGIC);
+
+    let mut ihdr = Vec::with_capacity(13);
+    push_u32(&mut ihdr, image.width);
+    push_u32(&mut ihdr, image.height);
+    ihdr.push_all([bits, color_type, 0, 0, 0]);
+    write_chunk(&mut png, "IHDR", ihdr.as_slice());
+
+    match palette {
+        Some(palette) => {
+            if palette.rgb.len() != palette.alpha.len() * 3 {
+                return Err(Malformed(format!("palette has {} bytes of colors for {} alpha values",
+                                             palette.rgb.len(), palette.alpha.len())));
+            }
+            // At most 256 entries, for 8 bits per pixel.
+            if palette.len() == 0 || palette.len() > 1 << bits as uint {
+                return Err(Malformed(format!("can't encode a palette of {} entries with {} bits per pixel",
+                                             palette.len(), bits)));
+            }
+            write_chunk(&mut png, "PLTE", palette.rgb.as_slice());
+            // tRNS can leave out the opaque entries at the end.
+            let trns_len = palette.alpha.iter().rposition(|&a| a != 0xff).map_or(0, |i| i + 1);
+            if trns_len > 0 {
+                write_chunk(&mut png, "tRNS", palette.alpha.slice_to(trns_len));
+            }
+        }
+        None => {}
+    }
+
+    // Gray and RGB images can have a single transparent color instead.
+    match image.transparent_color {
+        Some(color) if color_type == 0 || color_type == 2 => {
+            let samples = if color_type == 0 { color.slice_to(1) } else { color.as_slice() };
+            let mut trns = Vec::with_capacity(6);
+            for &v in samples.iter() {
+                if bits < 16 && v >= 1 << bits as uint {
+                    return Err(Malformed(format!("transparent color sample {} doesn't fit in {} bits",
+                                                 v, bits)));
+                }
+                trns.push_all([(v >> 8) as u8, v as u8]);
+            }
+            write_chunk(&mut png, "tRNS", trns.as_slice());
+        }
+        _ => {}
+    }
+
+    // Every scanline is stored with no filter (type 0).
+    let mut raw = Vec::with_capacity((scanline_bytes + 1) * h);
+    for line in image.pixels.as_slice().chunks(scanline_bytes) {
+        raw.push(0u8);
+        // Other layouts are stored as RGBA.
+        match (image.color_type, image.layout) {
+            (RGBA8, LayoutBGRA8) => for p in line.chunks(4) {
+                raw.push_all([p[2], p[1], p[0], p[3]]);
+            },
+            (RGBA8, LayoutARGB8) => for p in line.chunks(4) {
+                raw.push_all([p[1], p[2], p[3], p[0]]);
+            },
+            _ => raw.push_all(line)
+        }
+    }
+
+    let mut stream = DeflateStream::to_zlib(COMPRESSION_LEVEL);
+    let mut zlib = Vec::new();
+    zlib.push_all(stream.update(raw.as_slice()));
+    zlib.push_all(stream.finish());
+    for idat in zlib.as_slice().chunks(IDAT_SIZE) {
+        write_chunk(&mut png, "IDAT", idat);
+    }
+
+    write_chunk(&mut png, "IEND", []);
+    Ok(png)
+}
+
+pub fn save_png(path: &Path, image: &Image) -> Result<(), PngError> {
+    let png = match encode_png_to_memory(image) {
+        Ok(png) => png,
+        Err(e) => return Err(e)
+    };
+    match File::create(path) {
+        Ok(mut w) => match w.write(png.as_slice()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Io(e))
+        },
+        Err(e) => Err(Io(e))
+    }
+}
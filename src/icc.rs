@@ -0,0 +1,215 @@
+// Copyright 2014 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A minimal interpreter of ICC profiles (v2 and v4), for the matrix/TRC RGB
+// and gray TRC profiles written by most image editors. Profiles built from
+// lookup tables (AToB and the like) aren't supported.
+
+use std::str::from_utf8;
+
+use super::{PngError, Malformed, Unsupported, read_u32};
+use color::{Matrix, SrgbTransform, invert, to_srgb_matrix};
+
+// A tone reproduction curve, from device values to linear light.
+pub enum Curve {
+    // Samples of the curve, evenly spaced from 0 to 1 (curv).
+    CurveTable(Vec<u16>),
+    // One of the five parametric functions and its parameters (para), where
+    // a plain gamma is function 0.
+    CurveParametric(u16, [f64, ..7])
+}
+
+impl Curve {
+    pub fn eval(&self, x: f64) -> f64 {
+        match *self {
+            CurveTable(ref table) => {
+                let table = table.as_slice();
+                let pos = x * (table.len() - 1) as f64;
+                let i = pos as uint;
+                if i + 1 >= table.len() {
+                    return table[table.len() - 1] as f64 / 65535.0;
+                }
+                let (a, b) = (table[i] as f64, table[i + 1] as f64);
+                (a + (b - a) * (pos - i as f64)) / 65535.0
+            }
+            CurveParametric(function, p) => {
+                let (g, a, b, c, d, e, f) = (p[0], p[1], p[2], p[3], p[4], p[5], p[6]);
+                let pow = |v: f64| if v > 0.0 { v.powf(g) } else { 0.0 };
+                match function {
+                    0 => pow(x),
+                    1 => if x >= -b / a { pow(a * x + b) } else { 0.0 },
+                    2 => if x >= -b / a { pow(a * x + b) + c } else { c },
+                    3 => if x >= d { pow(a * x + b) } else { c * x },
+                    _ => if x >= d { pow(a * x + b) + e } else { c * x + f }
+                }
+            }
+        }
+    }
+}
+
+pub enum Profile {
+    // Colorants (as the columns of an RGB to XYZ matrix), the PCS white point
+    // they're relative to and the red, green and blue curves.
+    MatrixTrc(Matrix, [f64, ..3], Vec<Curve>),
+    GrayTrc(Curve)
+}
+
+impl Profile {
+    pub fn parse(data: &[u8]) -> Result<Profile, PngError> {
+        if data.len() < 132 || signature(data, 36) != "acsp" {
+            return Err(Malformed("invalid ICC profile header".to_string()));
+        }
+        let size = read_u32(data, 0) as uint;
+        if size < 132 || size > data.len() {
+            return Err(Malformed(format!("invalid ICC profile size {}", size)));
+        }
+        let data = data.slice_to(size);
+        let version = data[8];
+        if version < 2 || version > 4 {
+            return Err(Unsupported(format!("unsupported ICC profile version {}", version)));
+        }
+        if signature(data, 20) != "XYZ " {
+            return Err(Unsupported("ICC profiles with a Lab PCS aren't supported".to_string()));
+        }
+        let white = try!(s15_fixed16s(data, 68));
+        // Colors are adapted from it, which needs it to be a real illuminant.
+        if !white.iter().all(|&v| v > 0.0 && v.is_finite()) {
+            return Err(Malformed("invalid ICC PCS illuminant".to_string()));
+        }
+
+        let tag_count = read_u32(data, 128) as uint;
+        if tag_count > (size - 132) / 12 {
+            return Err(Malformed(format!("invalid ICC tag count {}", tag_count)));
+        }
+        let mut tags = Vec::with_capacity(tag_count);
+        for i in range(0, tag_count) {
+            let entry = data.slice(132 + i * 12, 144 + i * 12);
+            let (offset, len) = (read_u32(entry, 4) as uint, read_u32(entry, 8) as uint);
+            if offset > size || len > size - offset {
+                return Err(Malformed("ICC tag out of bounds".to_string()));
+            }
+            tags.push((signature(entry, 0), data.slice(offset, offset + len)));
+        }
+        let tags = tags.as_slice();
+        let tag = |name: &str| find_tag(tags, name);
+
+        match signature(data, 16) {
+            "RGB " => {
+                match (tag("rXYZ"), tag("gXYZ"), tag("bXYZ"), tag("rTRC"), tag("gTRC"), tag("bTRC")) {
+                    (Some(r), Some(g), Some(b), Some(r_trc), Some(g_trc), Some(b_trc)) => {
+                        let (r, g, b) = (try!(xyz_tag(r)), try!(xyz_tag(g)), try!(xyz_tag(b)));
+                        let matrix = [[r[0], g[0], b[0]],
+                                      [r[1], g[1], b[1]],
+                                      [r[2], g[2], b[2]]];
+                        if invert(&matrix).is_none() {
+                            return Err(Malformed("singular ICC colorant matrix".to_string()));
+                        }
+                        let curves = vec![try!(curve_tag(r_trc)), try!(curve_tag(g_trc)),
+                                          try!(curve_tag(b_trc))];
+                        Ok(MatrixTrc(matrix, white, curves))
+                    }
+                    _ => Err(lut_or_malformed(tag("A2B0").is_some() || tag("A2B1").is_some() ||
+                                              tag("A2B2").is_some()))
+                }
+            }
+            "GRAY" => match tag("kTRC") {
+                Some(k_trc) => Ok(GrayTrc(try!(curve_tag(k_trc)))),
+                None => Err(lut_or_malformed(tag("A2B0").is_some()))
+            },
+            space => Err(Unsupported(format!("unsupported ICC color space {}", space)))
+        }
+    }
+
+    // The conversion of samples described by the profile to sRGB. Gray
+    // samples are expected in all three channels.
+    pub fn to_srgb(&self, sixteen: bool) -> SrgbTransform {
+        match *self {
+            MatrixTrc(ref m, white, ref curves) => {
+                let curves = curves.as_slice();
+                SrgbTransform::new(to_srgb_matrix(m, white), sixteen, |c, v| curves[c].eval(v))
+            }
+            GrayTrc(ref curve) => SrgbTransform::new(None, sixteen, |_, v| curve.eval(v))
+        }
+    }
+}
+
+fn find_tag<'a>(tags: &[(&str, &'a [u8])], name: &str) -> Option<&'a [u8]> {
+    tags.iter().find(|&&(s, _)| s == name).map(|&(_, t)| t)
+}
+
+// Tag, type and color space signatures.
+fn signature<'a>(data: &'a [u8], i: uint) -> &'a str {
+    from_utf8(data.slice(i, i + 4)).unwrap_or("????")
+}
+
+fn lut_or_malformed(lut: bool) -> PngError {
+    if lut {
+        Unsupported("ICC profiles based on lookup tables aren't supported".to_string())
+    } else {
+        Malformed("ICC profile without colorant or TRC tags".to_string())
+    }
+}
+
+fn s15_fixed16s(data: &[u8], i: uint) -> Result<[f64, ..3], PngError> {
+    if data.len() < i + 12 {
+        return Err(Malformed("truncated ICC XYZ value".to_string()));
+    }
+    let fixed = |i: uint| read_u32(data, i) as i32 as f64 / 65536.0;
+    Ok([fixed(i), fixed(i + 4), fixed(i + 8)])
+}
+
+fn xyz_tag(data: &[u8]) -> Result<[f64, ..3], PngError> {
+    if data.len() < 8 || signature(data, 0) != "XYZ " {
+        return Err(Malformed("invalid ICC colorant tag".to_string()));
+    }
+    s15_fixed16s(data, 8)
+}
+
+fn curve_tag(data: &[u8]) -> Result<Curve, PngError> {
+    if data.len() < 12 {
+        return Err(Malformed("truncated ICC curve".to_string()));
+    }
+    match signature(data, 0) {
+        "curv" => {
+            let count = read_u32(data, 8) as uint;
+            if count > (data.len() - 12) / 2 {
+                return Err(Malformed("truncated ICC curve".to_string()));
+            }
+            let entry = |i: uint| (data[12 + i * 2] as u16 << 8) | data[13 + i * 2] as u16;
+            let mut p = [0.0, ..7];
+            match count {
+                // The identity, and a gamma as u8Fixed8.
+                0 => p[0] = 1.0,
+                1 => p[0] = entry(0) as f64 / 256.0,
+                _ => return Ok(CurveTable(Vec::from_fn(count, |i| entry(i))))
+            }
+            Ok(CurveParametric(0, p))
+        }
+        "para" => {
+            let function = (data[8] as u16 << 8) | data[9] as u16;
+            let params = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(Unsupported(format!("unknown ICC parametric curve {}", function)))
+            };
+            if data.len() < 12 + params * 4 {
+                return Err(Malformed("truncated ICC curve".to_string()));
+            }
+            let mut p = [0.0, ..7];
+            for i in range(0, params) {
+                p[i] = read_u32(data, 12 + i * 4) as i32 as f64 / 65536.0;
+            }
+            Ok(CurveParametric(function, p))
+        }
+        _ => Err(Malformed("invalid ICC curve tag".to_string()))
+    }
+}
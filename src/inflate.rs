@@ -11,8 +11,46 @@
 
 use std::iter::range_inclusive;
 use std::cmp;
+use std::fmt;
 use std::slice;
 
+use checksum::Adler32;
+
+#[deriving(PartialEq, Eq, Clone)]
+pub enum InflateError {
+    // Bad CMF/FLG bytes at the start of a ZLIB stream.
+    InvalidZlibHeader(String),
+    // FDICT is set but no dictionary was supplied (DICTID).
+    MissingDictionary(u32),
+    // The supplied dictionary doesn't match DICTID (expected, computed).
+    DictionaryMismatch(u32, u32),
+    // Malformed DEFLATE data.
+    InvalidDeflate(String),
+    // The ZLIB trailer doesn't match the output (expected, computed).
+    Adler32Mismatch(u32, u32),
+    // Number of bytes found after the end of a ZLIB stream.
+    TrailingData(uint)
+}
+
+impl fmt::Show for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidZlibHeader(ref m) | InvalidDeflate(ref m) => write!(f, "{}", m),
+            MissingDictionary(id) => {
+                write!(f, "ZLIB stream needs a preset dictionary with DICTID={:08x}", id)
+            }
+            DictionaryMismatch(id, computed) => {
+                write!(f, "ZLIB preset dictionary mismatch, DICTID={:08x} but the dictionary has {:08x}",
+                       id, computed)
+            }
+            Adler32Mismatch(expected, computed) => {
+                write!(f, "ZLIB Adler-32 mismatch, expected {:08x} but computed {:08x}", expected, computed)
+            }
+            TrailingData(n) => write!(f, "{} bytes of trailing data after the end of the ZLIB stream", n)
+        }
+    }
+}
+
 static BIT_REV_U8: [u8, ..256] = [
     0b0000_0000, 0b1000_0000, 0b0100_0000, 0b1100_0000,
     0b0010_0000, 0b1010_0000, 0b0110_0000, 0b1110_0000,
@@ -109,13 +147,6 @@ struct BitStream<'a> {
     state: BitState
 }
 
-// Use this instead of triggering a failure (that may unwind).
-fn abort() -> ! {
-    unsafe {
-        ::std::intrinsics::abort()
-    }
-}
-
 impl<'a> BitStream<'a> {
     fn new(bytes: &'a [u8], state: BitState) -> BitStream<'a> {
         BitStream {
@@ -138,15 +169,12 @@ impl<'a> BitStream<'a> {
     }
 
     fn need(&mut self, n: u8) -> bool {
+        assert!(n <= 16);
         if self.state.n < n {
             if !self.use_byte() {
                 return false;
             }
             if n > 8 && self.state.n < n {
-                if n > 16 {
-                    // HACK(eddyb) in place of a static assert.
-                    abort();
-                }
                 if !self.use_byte() {
                     return false;
                 }
@@ -196,6 +224,34 @@ macro_rules! with_codes (($clens:expr, $max_bits:expr => $code_ty:ty, $cb:expr)
     }
 }))
 
+// Check that code lengths describe a usable Huffman code, following zlib:
+// it can't be over-subscribed, and it can only be incomplete if it has
+// no codes at all, or a single code of length 1 (unless `complete` is set).
+fn check_code_lengths(clens: &[u8], max_bits: u8, kind: &str, complete: bool) -> Result<(), InflateError> {
+    let mut bl_count = [0u16, ..16];
+    for &bits in clens.iter() {
+        bl_count[bits as uint] += 1;
+    }
+
+    let mut left = 1i32;
+    let mut max = 0;
+    for bits in range_inclusive(1, max_bits) {
+        left = (left << 1) - bl_count[bits as uint] as i32;
+        if left < 0 {
+            return Err(InvalidDeflate(format!("over-subscribed DEFLATE {} code ({} codes of length {})",
+                                              kind, bl_count[bits as uint], bits)));
+        }
+        if bl_count[bits as uint] != 0 {
+            max = bits;
+        }
+    }
+
+    if left > 0 && max != 0 && (complete || max != 1) {
+        return Err(InvalidDeflate(format!("incomplete DEFLATE {} code", kind)));
+    }
+    Ok(())
+}
+
 struct CodeLengthReader {
     patterns: ~[u8, ..128],
     clens: ~[u8, ..19],
@@ -205,7 +261,9 @@ struct CodeLengthReader {
 }
 
 impl CodeLengthReader {
-    fn new(clens: ~[u8, ..19], num_lit: u16, num_dist: u8) -> CodeLengthReader {
+    fn new(clens: ~[u8, ..19], num_lit: u16, num_dist: u8) -> Result<CodeLengthReader, InflateError> {
+        try!(check_code_lengths(clens.as_slice(), 7, "code length", true));
+
         // Fill in the 7-bit patterns that match each code.
         let mut patterns = box() ([0xffu8, ..128]);
         with_codes!(clens, 7 => u8, |i: u8, code: u8, bits| {
@@ -215,56 +273,70 @@ impl CodeLengthReader {
             }
         });
 
-        CodeLengthReader {
+        Ok(CodeLengthReader {
             patterns: patterns,
             clens: clens,
             result: Vec::with_capacity(num_lit as uint + num_dist as uint),
             num_lit: num_lit,
             num_dist: num_dist
-        }
+        })
     }
 
-    fn read(&mut self, stream: &mut BitStream) -> bool {
+    // Returns Ok(true) once all the code lengths have been read.
+    fn read(&mut self, stream: &mut BitStream) -> Result<bool, InflateError> {
         let total_len = self.num_lit as uint + self.num_dist as uint;
         while self.result.len() < total_len {
-            if !stream.need(7) {
-                break;
+            // Near the end of the data, a short code may still be complete.
+            let has7 = stream.need(7);
+            let code = self.patterns[(stream.state.v & 0x7f) as uint];
+            if code == 0xff {
+                if has7 {
+                    return Err(InvalidDeflate("invalid DEFLATE code length code".to_string()));
+                }
+                return Ok(false);
+            }
+            let bits = self.clens[code as uint];
+            if !has7 && bits > stream.state.n {
+                return Ok(false);
             }
             let save = stream.state;
+            stream.take(bits);
             macro_rules! take (($n:expr) => (match stream.take($n) {
                 Some(v) => v,
                 None => {
                     stream.state = save;
-                    return false;
+                    return Ok(false);
                 }
             }))
-            let code = self.patterns[(stream.state.v & 0x7f) as uint];
-            stream.take(self.clens[code as uint]);
-            match code {
-                0..15 => self.result.push(code),
-                16 => {
-                    let last = *self.result.last().unwrap();
-                    for _ in range(0, 3 + take!(2)) {
-                        self.result.push(last);
-                    }
-                }
-                17 => for _ in range(0, 3 + take!(3)) {
-                    self.result.push(0);
+            let (value, repeat) = match code {
+                0..15 => (code, 1),
+                16 => match self.result.last() {
+                    Some(&last) => (last, 3 + take!(2)),
+                    None => return Err(InvalidDeflate("DEFLATE code length repeat (16) without a previous length".to_string()))
                 },
-                18 => for _ in range(0, 11 + take!(7)) {
-                    self.result.push(0);
-                },
-                _ => abort()
+                17 => (0, 3 + take!(3)),
+                18 => (0, 11 + take!(7)),
+                _ => return Err(InvalidDeflate(format!("invalid DEFLATE code length code {}", code)))
+            };
+            if self.result.len() + repeat as uint > total_len {
+                return Err(InvalidDeflate(format!("DEFLATE code length repeat overflows HLIT+HDIST ({} lengths)", total_len)));
+            }
+            for _ in range(0, repeat) {
+                self.result.push(value);
             }
         }
-        true
+        Ok(true)
     }
 
-    fn to_lit_and_dist(self) -> (DynHuffman16, DynHuffman16) {
+    fn to_lit_and_dist(self) -> Result<(DynHuffman16, DynHuffman16), InflateError> {
         let num_lit = self.num_lit as uint;
-        let lit = DynHuffman16::new(self.result.slice_to(num_lit));
-        let dist = DynHuffman16::new(self.result.slice_from(num_lit));
-        (lit, dist)
+        let (lit_clens, dist_clens) = (self.result.slice_to(num_lit), self.result.slice_from(num_lit));
+        if lit_clens[256] == 0 {
+            return Err(InvalidDeflate("DEFLATE lit/len code is missing the end-of-block code".to_string()));
+        }
+        let lit = try!(DynHuffman16::new(lit_clens, "lit/len"));
+        let dist = try!(DynHuffman16::new(dist_clens, "distance"));
+        Ok((lit, dist))
     }
 }
 
@@ -273,13 +345,18 @@ struct Trie8bit<T> {
     children: [Option<~[T, ..16]>, ..16]
 }
 
+// Returned by DynHuffman16::read for bits that don't start any code.
+static INVALID_CODE: u16 = 0xfff;
+
 struct DynHuffman16 {
     patterns: ~[u16, ..256],
     rest: Vec<Trie8bit<u16>>
 }
 
 impl DynHuffman16 {
-    fn new(clens: &[u8]) -> DynHuffman16 {
+    fn new(clens: &[u8], kind: &str) -> Result<DynHuffman16, InflateError> {
+        try!(check_code_lengths(clens, 15, kind, false));
+
         // Fill in the 8-bit patterns that match each code.
         // Longer patterns go into the trie.
         let mut patterns = box() ([0xffffu16, ..256]);
@@ -328,10 +405,10 @@ impl DynHuffman16 {
                 }
             }
         });
-        DynHuffman16 {
+        Ok(DynHuffman16 {
             patterns: patterns,
             rest: rest
-        }
+        })
     }
 
     fn read(&self, stream: &mut BitStream) -> Option<(BitState, u16)> {
@@ -348,6 +425,8 @@ impl DynHuffman16 {
             } else {
                 None
             }
+        } else if entry == 0xffff {
+            Some((stream.state, INVALID_CODE))
         } else if bits <= 8 {
             let save = stream.state;
             stream.state.n -= bits;
@@ -377,14 +456,18 @@ impl DynHuffman16 {
 enum State {
     ZlibMethodAndFlags, // CMF
     ZlibFlags(/* CMF */ u8), // FLG,
+    ZlibDictId(/* offset */ u8, /* value */ u32), // DICTID
     Bits(BitsNext, BitState),
     LenDist((BitsNext, BitState), /* len */ u16, /* dist */ u16),
-    CheckCRC
+    Uncompressed(/* left */ u16),
+    CheckAdler32(/* offset */ u8, /* value */ u32),
+    Done
 }
 
 enum BitsNext {
     BlockHeader,
     BlockUncompressed,
+    BlockUncompressedNlen(/* len */ u16),
     BlockFixed,
     BlockDynHlit,
     BlockDynHdist(/* hlit */ u8),
@@ -399,6 +482,15 @@ pub struct InflateStream {
     pos: u16,
     state: Option<State>,
     final_block: bool,
+    zlib: bool,
+    adler: Adler32,
+    // Position in buffer up to which output has been added to adler.
+    adler_pos: u16,
+    // Position in buffer from which output hasn't been returned yet.
+    out_pos: u16,
+    dictionary: Option<Vec<u8>>,
+    // Number of input bytes used so far.
+    total_in: u64
 }
 
 impl InflateStream {
@@ -411,7 +503,9 @@ impl InflateStream {
 
     #[allow(dead_code)]
     pub fn from_zlib() -> InflateStream {
-        InflateStream::with_state_and_buffer(ZlibMethodAndFlags, Vec::new())
+        let mut stream = InflateStream::with_state_and_buffer(ZlibMethodAndFlags, Vec::new());
+        stream.zlib = true;
+        stream
     }
 
     fn with_state_and_buffer(state: State, buffer: Vec<u8>) -> InflateStream {
@@ -419,7 +513,64 @@ impl InflateStream {
             buffer: buffer,
             pos: 0,
             state: Some(state),
-            final_block: false
+            final_block: false,
+            zlib: false,
+            adler: Adler32::new(),
+            adler_pos: 0,
+            out_pos: 0,
+            dictionary: None,
+            total_in: 0
+        }
+    }
+
+    // Supply the preset dictionary for a ZLIB stream with FDICT set.
+    // It's checked against the stream's DICTID before being used.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.dictionary = Some(Vec::from_slice(dictionary));
+    }
+
+    // Check the dictionary against DICTID and make it the start of the window,
+    // so that back-references can reach into it.
+    fn prime_dictionary(&mut self, id: u32) -> Result<(), InflateError> {
+        let dictionary = match self.dictionary.take() {
+            Some(dictionary) => dictionary,
+            None => return Err(MissingDictionary(id))
+        };
+        let mut adler = Adler32::new();
+        adler.update(dictionary.as_slice());
+        if adler.checksum() != id {
+            return Err(DictionaryMismatch(id, adler.checksum()));
+        }
+        // Only the end of a long dictionary fits in the window.
+        let start = dictionary.len() - cmp::min(dictionary.len(), self.buffer.capacity());
+        self.buffer.push_all(dictionary.slice_from(start));
+        self.pos = self.buffer.len() as u16;
+        self.adler_pos = self.pos;
+        self.out_pos = self.pos;
+        Ok(())
+    }
+
+    fn update_adler(&mut self) {
+        let (start, end) = (self.adler_pos as uint, self.pos as uint);
+        self.adler.update(self.buffer.slice(start, end));
+        self.adler_pos = self.pos;
+    }
+
+    // The state after the final block, from what's left of the bit buffer.
+    // Any whole bytes in it are the start of the ZLIB trailer, if there is one,
+    // otherwise the number of those bytes is returned, as they weren't used.
+    fn end_state(&self, state: BitState) -> (State, u8) {
+        let bytes = state.n / 8;
+        if self.zlib {
+            let mut v = state.v >> (state.n % 8);
+            let mut value = 0;
+            for _ in range(0, bytes) {
+                value = (value << 8) | (v & 0xff);
+                v >>= 8;
+            }
+            (CheckAdler32(bytes, value), 0)
+        } else {
+            (Done, bytes)
         }
     }
 
@@ -482,7 +633,17 @@ impl InflateStream {
         left
     }
 
-    fn next_state(&mut self, data: &[u8]) -> Result<uint, String> {
+    // Copy bytes (e.g. from a stored block) at the current position.
+    fn copy_bytes(&mut self, bytes: &[u8]) {
+        let pos = self.pos as uint;
+        let end = pos + bytes.len();
+        let overwrite = cmp::min(end, self.buffer.len()) - pos;
+        self.buffer.mut_slice(pos, pos + overwrite).copy_from(bytes.slice_to(overwrite));
+        self.buffer.push_all(bytes.slice_from(overwrite));
+        self.pos = end as u16;
+    }
+
+    fn next_state(&mut self, data: &[u8]) -> Result<uint, InflateError> {
         macro_rules! ok_bytes (($n:expr, $state:expr) => ({
             self.state = Some($state);
             Ok($n)
@@ -495,7 +656,8 @@ impl InflateStream {
                 self.buffer.as_mut_slice()[self.pos as uint] = b;
             } else {
                 if (self.pos as uint) != self.buffer.len() {
-                    abort();
+                    return Err(InvalidDeflate(format!("DEFLATE output position {} is past the end of the window ({})",
+                                                      self.pos, self.buffer.len())));
                 }
                 self.buffer.push(b);
             }
@@ -505,6 +667,10 @@ impl InflateStream {
         }))
         macro_rules! run_len_dist (($len:expr, $dist:expr => ($bytes:expr, $next:expr, $state:expr)) => ({
             let dist = $dist;
+            if dist as uint > self.buffer.len() {
+                return Err(InvalidDeflate(format!("invalid DEFLATE distance {} with only {} bytes of history",
+                                                  dist, self.buffer.len())));
+            }
             let left = self.run_len_dist($len, dist);
             match left {
                 Some(len) => {
@@ -520,11 +686,11 @@ impl InflateStream {
                 // debug!("ZLIB CM=0x{:x} CINFO=0x{:x}", method, info);
                 match method {
                     8 => {/* DEFLATE */}
-                    _ => return Err(format!("unknown ZLIB method CM=0x{:x}", method))
+                    _ => return Err(InvalidZlibHeader(format!("unknown ZLIB method CM=0x{:x}", method)))
                 }
 
                 if info > 7 {
-                    return Err(format!("invalid ZLIB info CINFO=0x{:x}", info));
+                    return Err(InvalidZlibHeader(format!("invalid ZLIB info CINFO=0x{:x}", info)));
                 }
 
                 self.buffer = Vec::with_capacity(1 << (8 + info));
@@ -537,19 +703,33 @@ impl InflateStream {
                 // debug!("ZLIB FCHECK=0x{:x} FDICT={} FLEVEL=0x{:x}", check, dict, _level);
 
                 if ((cmf as u16 << 8) | b as u16) % 31 != 0 {
-                    return Err(format!("invalid ZLIB checksum CMF=0x{:x} FLG=0x{:x}", cmf, b));
+                    return Err(InvalidZlibHeader(format!("invalid ZLIB checksum CMF=0x{:x} FLG=0x{:x}", cmf, b)));
                 }
 
                 if dict {
-                    return Err("unimplemented ZLIB FDICT=1".to_string());
+                    return ok_bytes!(1, ZlibDictId(0, 0));
                 }
 
                 ok_bytes!(1, Bits(BlockHeader, BitState { n: 0, v: 0 }))
             }
+            ZlibDictId(i, value) => {
+                let value = (value << 8) | data[0] as u32;
+                if i < 3 {
+                    return ok_bytes!(1, ZlibDictId(i + 1, value));
+                }
+                try!(self.prime_dictionary(value));
+                ok_bytes!(1, Bits(BlockHeader, BitState { n: 0, v: 0 }))
+            }
             Bits(next, state) => {
                 let mut stream = BitStream::new(data, state);
                 macro_rules! ok_state (($state:expr) => (ok_bytes!(stream.used, $state)))
                 macro_rules! ok (($next:expr) => (ok_state!(Bits($next, stream.state))))
+                macro_rules! end_block (() => (if self.final_block {
+                    let (state, unused) = self.end_state(stream.state);
+                    ok_bytes!(stream.used - cmp::min(unused as uint, stream.used), state)
+                } else {
+                    ok!(BlockHeader)
+                }))
                 macro_rules! need (($n:expr) => (if !stream.need($n) { return ok!(next); }))
                 macro_rules! take (
                     ($n:expr => $next:expr) => (match stream.take($n) {
@@ -584,15 +764,18 @@ impl InflateStream {
                         let (final, block_type) = ((h & 1) != 0, (h >> 1) & 0b11);
 
                         if self.final_block {
-                            return Err("DEFLATE data after the final block".to_string());
+                            return Err(InvalidDeflate("DEFLATE data after the final block".to_string()));
                         }
 
                         self.final_block = final;
 
                         match block_type {
                             0 => {
-                                // Skip to the next byte for an uncompressed block.
-                                stream.state = BitState { n: 0, v: 0 };
+                                // Skip to the next byte for an uncompressed block,
+                                // keeping any whole bytes that were already read.
+                                let skip = stream.state.n % 8;
+                                stream.state.v >>= skip;
+                                stream.state.n -= skip;
                                 ok!(BlockUncompressed)
                             }
                             1 => {
@@ -627,13 +810,23 @@ impl InflateStream {
                                 ok!(BlockFixed)
                             }
                             2 => ok!(BlockDynHlit),
-                            _ => Err(format!("unimplemented DEFLATE block type 0b{:t}",
-                                             block_type))
+                            _ => Err(InvalidDeflate(format!("unimplemented DEFLATE block type 0b{:t}",
+                                                            block_type)))
                         }
                     }
                     BlockUncompressed => {
                         let len = take16!(16);
-                        fail!("uncompressed len={}", len)
+                        ok!(BlockUncompressedNlen(len))
+                    }
+                    BlockUncompressedNlen(len) => {
+                        let nlen = take16!(16);
+                        if len != !nlen {
+                            return Err(InvalidDeflate(format!("invalid DEFLATE stored block length LEN={} NLEN={}",
+                                                              len, nlen)));
+                        }
+                        // LEN and NLEN end on a byte boundary, with nothing left in
+                        // the bit buffer, so the data can be copied byte by byte.
+                        ok_state!(Uncompressed(len))
                     }
                     BlockFixed => {
                         macro_rules! len_dist2 (($len:expr, $code_const:expr, $code_rev:expr, $bits:expr) => ({
@@ -666,7 +859,7 @@ impl InflateStream {
                                 0b00011 | 0b10011 => len_dist2!(len, 24, code, 11),
                                 0b01011 | 0b11011 => len_dist2!(len, 26, code, 12),
                                 0b00111 | 0b10111 => len_dist2!(len, 28, code, 13),
-                                _ => return Err(format!("bad DEFLATE dist code {}", code))
+                                _ => return Err(InvalidDeflate(format!("bad DEFLATE dist code {}", code)))
                             }
                         }))
                         loop {
@@ -678,17 +871,13 @@ impl InflateStream {
                                 // FIXME(eddyb) use a 7-bit rev LUT or match the huffman code directly.
                                 let code = BIT_REV_U8[(stream.take(7).unwrap() << 1) as uint];
                                 match code {
-                                    0 => return if self.final_block {
-                                        ok_state!(CheckCRC)
-                                    } else {
-                                        ok!(BlockHeader)
-                                    },
+                                    0 => return end_block!(),
                                     1..8 => len!(code, 0),
                                     9..12 => len!(code, 1),
                                     13..16 => len!(code, 2),
                                     17..20 => len!(code, 3),
                                     21..23 => len!(code, 4),
-                                    _ => return Err(format!("bad DEFLATE len code {}", code as u16 + 256))
+                                    _ => return Err(InvalidDeflate(format!("bad DEFLATE len code {}", code as u16 + 256)))
                                 };
                                 //if do_return { return ok!(Bits(next, save)) }
                                 continue;
@@ -714,7 +903,7 @@ impl InflateStream {
                                     24 => len!(24, 4),
                                     25..28 => len!(code, 5),
                                     29 => len!(29, 0),
-                                    _ => return Err(format!("bad DEFLATE len code {}", code as u16 + 256))
+                                    _ => return Err(InvalidDeflate(format!("bad DEFLATE len code {}", code as u16 + 256)))
                                 };
                                 //if do_return { return ok!(Bits(next, save)) }
                                 continue;
@@ -730,7 +919,14 @@ impl InflateStream {
                         }
                     }
                     BlockDynHlit => ok!(BlockDynHdist(take!(5) + 1)),
-                    BlockDynHdist(hlit) => ok!(BlockDynHclen(hlit, take!(5) + 1)),
+                    BlockDynHdist(hlit) => {
+                        let hdist = take!(5) + 1;
+                        if hlit as u16 + 256 > 286 || hdist > 30 {
+                            return Err(InvalidDeflate(format!("too many DEFLATE codes, {} lit/len and {} distance",
+                                                              hlit as u16 + 256, hdist)));
+                        }
+                        ok!(BlockDynHclen(hlit, hdist))
+                    }
                     BlockDynHclen(hlit, hdist) => {
                         ok!(BlockDynClenCodeLengths(hlit, hdist, take!(4) + 4, 0, box() ([0, ..19])))
                     }
@@ -743,13 +939,14 @@ impl InflateStream {
                         if i < hclen - 1 {
                             ok!(BlockDynClenCodeLengths(hlit, hdist, hclen, i + 1, clens))
                         } else {
-                            ok!(BlockDynCodeLengths(CodeLengthReader::new(clens, hlit as u16 + 256, hdist)))
+                            let reader = try!(CodeLengthReader::new(clens, hlit as u16 + 256, hdist));
+                            ok!(BlockDynCodeLengths(reader))
                         }
                     }
                     BlockDynCodeLengths(mut reader) => {
-                        let finished = reader.read(&mut stream);
+                        let finished = try!(reader.read(&mut stream));
                         if finished {
-                            let (lit, dist) = reader.to_lit_and_dist();
+                            let (lit, dist) = try!(reader.to_lit_and_dist());
                             ok!(BlockDyn(lit, dist))
                         } else {
                             ok!(BlockDynCodeLengths(reader))
@@ -785,7 +982,10 @@ impl InflateStream {
                                 24..25 => len_dist!(len, code, 11 => BlockDyn(lit_len, dist)),
                                 26..27 => len_dist!(len, code, 12 => BlockDyn(lit_len, dist)),
                                 28..29 => len_dist!(len, code, 13 => BlockDyn(lit_len, dist)),
-                                _ => return Err(format!("bad DEFLATE dist code {}", code))
+                                _ if code == INVALID_CODE => {
+                                    return Err(InvalidDeflate("invalid DEFLATE distance Huffman code".to_string()))
+                                }
+                                _ => return Err(InvalidDeflate(format!("bad DEFLATE dist code {}", code)))
                             }
                         }))
                         loop {
@@ -803,14 +1003,13 @@ impl InflateStream {
                                     continue;
                                 }
                                 256..285 => {}
-                                _ => return Err(format!("bad DEFLATE len code {}", code))
+                                _ if code16 == INVALID_CODE => {
+                                    return Err(InvalidDeflate("invalid DEFLATE lit/len Huffman code".to_string()))
+                                }
+                                _ => return Err(InvalidDeflate(format!("bad DEFLATE len code {}", code16)))
                             }
                             match code {
-                                0 => return if self.final_block {
-                                    ok_state!(CheckCRC)
-                                } else {
-                                    ok!(BlockHeader)
-                                },
+                                0 => return end_block!(),
                                 1..8 => len!(code, 0),
                                 9..12 => len!(code, 1),
                                 13..16 => len!(code, 2),
@@ -818,7 +1017,7 @@ impl InflateStream {
                                 21..24 => len!(code, 4),
                                 25..28 => len!(code, 5),
                                 29 => len!(29, 0),
-                                _ => return Err(format!("bad DEFLATE len code {}", code as u16 + 256))
+                                _ => return Err(InvalidDeflate(format!("bad DEFLATE len code {}", code as u16 + 256)))
                             }
                         }
                         ok!(BlockDyn(lit_len, dist))
@@ -829,29 +1028,186 @@ impl InflateStream {
                 run_len_dist!(len, dist => (0, next, state));
                 ok_bytes!(0, Bits(next, state))
             }
-            CheckCRC => {
-                let _b = data[0];
-                //debug!("CRC check not implemented")
-                ok_bytes!(1, CheckCRC)
+            Uncompressed(left) => {
+                let space = self.buffer.capacity() - self.pos as uint;
+                let n = cmp::min(cmp::min(left as uint, data.len()), space);
+                self.copy_bytes(data.slice_to(n));
+                let left = left - n as u16;
+                if left > 0 {
+                    ok_bytes!(n, Uncompressed(left))
+                } else if self.final_block {
+                    let (state, _) = self.end_state(BitState { n: 0, v: 0 });
+                    ok_bytes!(n, state)
+                } else {
+                    ok_bytes!(n, Bits(BlockHeader, BitState { n: 0, v: 0 }))
+                }
             }
+            CheckAdler32(i, value) => {
+                let value = (value << 8) | data[0] as u32;
+                if i < 3 {
+                    ok_bytes!(1, CheckAdler32(i + 1, value))
+                } else {
+                    self.update_adler();
+                    let computed = self.adler.checksum();
+                    if value != computed {
+                        return Err(Adler32Mismatch(value, computed));
+                    }
+                    ok_bytes!(1, Done)
+                }
+            }
+            Done => Err(TrailingData(data.len()))
+        }
+    }
+
+    // Whether the end of the stream (including the ZLIB trailer) was reached.
+    pub fn is_done(&self) -> bool {
+        match self.state {
+            Some(Done) => true,
+            _ => false
         }
     }
 
+    // Number of input bytes used so far. After an error, that's the offset
+    // of the byte that couldn't be used.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
     #[allow(dead_code)]
-    pub fn update<'a>(&'a mut self, mut data: &[u8]) -> Result<(uint, &'a [u8]), String> {
+    pub fn update<'a>(&'a mut self, mut data: &[u8]) -> Result<(uint, &'a [u8]), InflateError> {
         let original_size = data.len();
-        let original_pos = self.pos as uint;
         while data.len() > 0 &&
             ((self.pos as uint) < self.buffer.capacity() || self.buffer.capacity() == 0) {
+            // Whatever follows a raw DEFLATE stream is left to the caller.
+            match self.state {
+                Some(Done) if !self.zlib => break,
+                _ => {}
+            }
             match self.next_state(data) {
-                Ok(n) => { data = data.slice_from(n); }
+                Ok(n) => {
+                    data = data.slice_from(n);
+                    self.total_in += n as u64;
+                }
                 Err(m) => return Err(m)
             }
         }
-        let output = self.buffer.as_slice().slice(original_pos, self.pos as uint);
+        if self.zlib {
+            self.update_adler();
+        }
+        let output = self.buffer.as_slice().slice(self.out_pos as uint, self.pos as uint);
+        self.out_pos = self.pos;
         if self.pos as uint >= self.buffer.capacity() {
             self.pos = 0;
+            self.adler_pos = 0;
+            self.out_pos = 0;
         }
         Ok((original_size - data.len(), output))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use checksum::Adler32;
+    use deflate::DeflateStream;
+    use super::{InflateStream, InflateError, InvalidDeflate, MissingDictionary, Adler32Mismatch, TrailingData};
+
+    fn try_inflate_zlib(mut data: &[u8]) -> Result<Vec<u8>, InflateError> {
+        let mut stream = InflateStream::from_zlib();
+        let mut out = Vec::new();
+        while data.len() > 0 {
+            let (used, output) = try!(stream.update(data));
+            out.push_all(output);
+            data = data.slice_from(used);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_inflate_malformed() {
+        fn inflate_raw_error(data: &[u8]) -> String {
+            match InflateStream::new().update(data) {
+                Err(InvalidDeflate(m)) => m,
+                Err(e) => fail!("unexpected error {}", e),
+                Ok(_) => fail!("malformed DEFLATE data wasn't detected")
+            }
+        }
+
+        // Fixed block starting with a length 3, distance 1 match.
+        let m = inflate_raw_error([0x03, 0x02, 0x00, 0x00]);
+        assert!(m.as_slice().contains("invalid DEFLATE distance 1"));
+
+        // Dynamic block whose first code length is a repeat (16).
+        let m = inflate_raw_error([0x05, 0x00, 0x02, 0x24, 0x00, 0x00]);
+        assert!(m.as_slice().contains("without a previous length"));
+
+        // Dynamic block with three code length codes of length 1.
+        let m = inflate_raw_error([0x05, 0x00, 0x92, 0x04, 0x00, 0x00]);
+        assert!(m.as_slice().contains("over-subscribed DEFLATE code length code"));
+    }
+
+    #[test]
+    fn test_inflate_dictionary() {
+        fn push_u32(out: &mut Vec<u8>, v: u32) {
+            for i in range(0u, 4) {
+                out.push((v >> (24 - 8 * i)) as u8);
+            }
+        }
+        let dictionary = "hello".as_bytes();
+        let mut adler = Adler32::new();
+        adler.update(dictionary);
+        let adler_dictionary = adler.checksum();
+
+        // FDICT set, then a fixed block with a length 3, distance 5 match.
+        let mut zlib = vec![0x78u8, 0x20];
+        push_u32(&mut zlib, adler_dictionary);
+        zlib.push_all([0x03, 0x12, 0x00]);
+        let mut adler = Adler32::new();
+        adler.update("hel".as_bytes());
+        push_u32(&mut zlib, adler.checksum());
+
+        match try_inflate_zlib(zlib.as_slice()) {
+            Err(e) => assert!(e == MissingDictionary(adler_dictionary)),
+            Ok(_) => fail!("missing dictionary wasn't detected")
+        }
+
+        let mut stream = InflateStream::from_zlib();
+        stream.set_dictionary("jello".as_bytes());
+        assert!(stream.update(zlib.as_slice()).is_err());
+
+        let mut stream = InflateStream::from_zlib();
+        stream.set_dictionary(dictionary);
+        let mut data = zlib.as_slice();
+        let mut out = Vec::new();
+        while data.len() > 0 {
+            let (used, output) = stream.update(data).unwrap();
+            out.push_all(output);
+            data = data.slice_from(used);
+        }
+        assert_eq!(out.as_slice(), "hel".as_bytes());
+    }
+
+    #[test]
+    fn test_adler32_trailer() {
+        let input = Vec::from_fn(70000, |i| (i * i / 7 % 251) as u8);
+        let mut stream = DeflateStream::to_zlib(6);
+        let mut zlib = Vec::from_slice(stream.update(input.as_slice()));
+        zlib.push_all(stream.finish());
+        assert_eq!(try_inflate_zlib(zlib.as_slice()).unwrap(), input);
+
+        let mut corrupt = zlib.clone();
+        let last = corrupt.len() - 1;
+        *corrupt.get_mut(last) ^= 1;
+        match try_inflate_zlib(corrupt.as_slice()) {
+            Err(Adler32Mismatch(expected, computed)) => assert!(expected != computed),
+            Err(e) => fail!("unexpected error {}", e),
+            Ok(_) => fail!("corrupted Adler-32 wasn't detected")
+        }
+
+        let mut trailing = zlib.clone();
+        trailing.push(0);
+        match try_inflate_zlib(trailing.as_slice()) {
+            Err(e) => assert!(e == TrailingData(1)),
+            Ok(_) => fail!("trailing data wasn't detected")
+        }
+    }
+}
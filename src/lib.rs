@@ -24,18 +24,31 @@ extern crate extra;
 
 use std::mem;
 use std::cmp::min;
+use std::fmt;
 use std::io;
-use std::io::File;
+use std::io::{File, IoError};
 use std::iter::range_step_inclusive;
 use std::mem::size_of;
 use std::num::abs;
 use std::str::from_utf8;
 
-use inflate::InflateStream;
+use checksum::Crc32;
+use color::SrgbTransform;
+use icc::Profile;
 
+pub use deflate::DeflateStream;
+pub use encoder::{encode_png_to_memory, save_png};
+pub use inflate::{InflateStream, InflateError, InvalidZlibHeader, MissingDictionary, DictionaryMismatch};
+pub use inflate::{InvalidDeflate, Adler32Mismatch, TrailingData};
+
+mod checksum;
+mod color;
+mod deflate;
+mod encoder;
+mod icc;
 mod inflate;
 
-#[deriving(PartialEq, Eq)]
+#[deriving(PartialEq, Eq, Clone, Show)]
 pub enum ColorType {
     K1, K2, K4, K8, K16,
     KA8, KA16,
@@ -52,6 +65,23 @@ impl ColorType {
         }
     }
 
+    fn is_gray(self) -> bool {
+        match self {
+            K1 | K2 | K4 | K8 | K16 | KA8 | KA16 => true,
+            _ => false
+        }
+    }
+
+    pub fn bit_depth(self) -> uint {
+        match self {
+            K1 | Pal1 => 1,
+            K2 | Pal2 => 2,
+            K4 | Pal4 => 4,
+            K16 | KA16 | RGB16 | RGBA16 => 16,
+            _ => 8
+        }
+    }
+
     fn pixel_bits(self) -> uint {
         match self {
             K1 | Pal1 => 1,
@@ -67,17 +97,291 @@ impl ColorType {
     }
 }
 
+// The image header, as found in IHDR.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    // The color type in the file, before any expansion.
+    pub color_type: ColorType,
+    pub compression_method: u8,
+    pub filter_method: u8,
+    // 0 for none, 1 for Adam7.
+    pub interlace_method: u8,
+    // Whether PLTE and tRNS were found, so far while decoding.
+    pub has_plte: bool,
+    pub has_trns: bool,
+    // Number of PLTE entries, 0 without PLTE.
+    pub palette_size: uint,
+    // The file gamma from gAMA, times 100000 (e.g. 45455 for 1/2.2).
+    pub gamma: Option<u32>
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct Palette {
+    // Red, green and blue of each entry, from PLTE.
+    pub rgb: Vec<u8>,
+    // Alpha of each entry, from tRNS (0xff past its end).
+    pub alpha: Vec<u8>
+}
+
+impl Palette {
+    fn from_rgba(rgba: &[u8]) -> Palette {
+        let mut palette = Palette {
+            rgb: Vec::with_capacity(rgba.len() / 4 * 3),
+            alpha: Vec::with_capacity(rgba.len() / 4)
+        };
+        for entry in rgba.chunks(4) {
+            palette.rgb.push_all(entry.slice_to(3));
+            palette.alpha.push(entry[3]);
+        }
+        palette
+    }
+
+    pub fn len(&self) -> uint {
+        self.alpha.len()
+    }
+
+    pub fn rgba(&self, index: uint) -> [u8, ..4] {
+        let rgb = self.rgb.slice(index * 3, index * 3 + 3);
+        [rgb[0], rgb[1], rgb[2], *self.alpha.get(index)]
+    }
+}
+
+// CIE x and y chromaticities from cHRM, times 100000.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct Chromaticities {
+    pub white: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32)
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct IccProfile {
+    pub name: String,
+    // Decompressed from iCCP.
+    pub data: Vec<u8>
+}
+
+impl IccProfile {
+    // Whether Transformations::to_srgb can use the profile: Unsupported for
+    // profiles based on lookup tables (or anything but matrix/TRC RGB and gray
+    // TRC profiles), and Malformed if it can't be parsed.
+    pub fn check(&self) -> Result<(), PngError> {
+        Profile::parse(self.data.as_slice()).map(|_| ())
+    }
+}
+
+// Coding-independent code points from cICP (as in ITU-T H.273).
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct Cicp {
+    pub primaries: u8,
+    pub transfer: u8,
+    pub matrix: u8,
+    pub full_range: bool
+}
+
+// The colorimetry chunks of an image, none of which are applied to the pixels.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct ColorInfo {
+    // Rendering intent from sRGB (0 to 3, perceptual to absolute colorimetric).
+    pub srgb_intent: Option<u8>,
+    pub chromaticities: Option<Chromaticities>,
+    pub icc_profile: Option<IccProfile>,
+    pub cicp: Option<Cicp>
+}
+
+impl ColorInfo {
+    pub fn new() -> ColorInfo {
+        ColorInfo {
+            srgb_intent: None,
+            chromaticities: None,
+            icc_profile: None,
+            cicp: None
+        }
+    }
+}
+
 pub struct Image {
     pub width: u32,
     pub height: u32,
     pub color_type: ColorType,
-    pub pixels: Vec<u8>
+    // 16-bit samples are kept in big-endian (PNG) byte order, see pixels_u16.
+    pub pixels: Vec<u8>,
+    // The image as found in the PNG file, before any expansion.
+    pub info: ImageInfo,
+    // The palette of palette images, also used to encode them.
+    pub palette: Option<Palette>,
+    // The tRNS color (or gray level, repeated) for images without a palette.
+    pub transparent_color: Option<[u16, ..3]>,
+    // Byte order of RGBA8 pixels (or RGB8, for LayoutRGB8), LayoutRGBA8
+    // unless another one was asked for with DecoderOptions::layout.
+    pub layout: PixelLayout,
+    // Whether color samples are multiplied by alpha, see unpremultiply.
+    pub premultiplied: bool,
+    pub color_info: ColorInfo
+}
+
+impl Image {
+    // An image with the info it would have if encoded as-is.
+    pub fn new(width: u32, height: u32, color_type: ColorType, pixels: Vec<u8>) -> Image {
+        Image {
+            width: width,
+            height: height,
+            color_type: color_type,
+            pixels: pixels,
+            info: ImageInfo {
+                width: width,
+                height: height,
+                bit_depth: color_type.bit_depth() as u8,
+                color_type: color_type,
+                compression_method: 0,
+                filter_method: 0,
+                interlace_method: 0,
+                has_plte: false,
+                has_trns: false,
+                palette_size: 0,
+                gamma: None
+            },
+            palette: None,
+            transparent_color: None,
+            layout: LayoutRGBA8,
+            premultiplied: false,
+            color_info: ColorInfo::new()
+        }
+    }
+
+    // Native-endian samples of a 16-bit image (KA16 or RGBA16).
+    pub fn pixels_u16(&self) -> Option<Vec<u16>> {
+        if self.color_type.bit_depth() != 16 {
+            return None;
+        }
+        Some(self.pixels.as_slice().chunks(2).map(|b| (b[0] as u16 << 8) | b[1] as u16).collect())
+    }
+
+    // Go back to straight alpha, e.g. before encoding a premultiplied image.
+    // The color of fully transparent pixels is lost, they stay black.
+    pub fn unpremultiply(&mut self) {
+        if !self.premultiplied {
+            return;
+        }
+        self.premultiplied = false;
+        let (pixel_bytes, alpha) = match (self.color_type, self.layout) {
+            (KA8, _) => (2, 1),
+            (RGBA8, LayoutARGB8) => (4, 0),
+            (RGBA8, _) => (4, 3),
+            _ => return
+        };
+        for pixel in self.pixels.as_mut_slice().mut_chunks(pixel_bytes) {
+            let a = pixel[alpha] as u16;
+            for (j, c) in pixel.mut_iter().enumerate() {
+                if j != alpha {
+                    *c = if a == 0 {
+                        0
+                    } else {
+                        min((*c as u16 * 255 + a / 2) / a, 255) as u8
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[deriving(PartialEq, Clone)]
+pub enum PngError {
+    // Reading (or writing) the PNG file failed.
+    Io(IoError),
+    // The signature doesn't match, at the given byte.
+    BadSignature(/*offset*/ u8),
+    // A chunk is missing, duplicated or out of place.
+    ChunkOrder(String),
+    // A chunk's CRC doesn't match its contents.
+    CrcMismatch(/*chunk*/ [u8, ..4], /*expected*/ u32, /*computed*/ u32),
+    // The IDAT data (or iCCP profile) couldn't be decompressed.
+    Inflate(InflateError),
+    // A chunk or the image data has an invalid value, or when encoding, the Image.
+    Malformed(String),
+    // Valid, but not supported by this decoder.
+    Unsupported(String),
+    // The image goes over one of the decoder's limits.
+    LimitExceeded(String),
+    // The data, or the image data before IEND, ended before the image was complete.
+    Truncated,
+    // The decoder was used after it returned a complete image or an error.
+    DecoderFinished
+}
+
+impl fmt::Show for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Io(ref e) => write!(f, "{}", e),
+            BadSignature(i) => {
+                write!(f, "PNG header mismatch, expected {:#02x} for byte {}", MAGIC[i as uint], i)
+            }
+            ChunkOrder(ref m) | Malformed(ref m) | Unsupported(ref m) | LimitExceeded(ref m) => {
+                write!(f, "{}", m)
+            }
+            CrcMismatch(name, expected, computed) => {
+                write!(f, "CRC mismatch in {} chunk, expected {:08x} but computed {:08x}",
+                       from_utf8(name).unwrap_or("????"), expected, computed)
+            }
+            Inflate(ref e) => write!(f, "ZLIB decompression error: {}", e),
+            Truncated => write!(f, "incomplete PNG file"),
+            DecoderFinished => write!(f, "the PNG decoder has already finished")
+        }
+    }
+}
+
+// Where in the PNG data an error was found.
+#[deriving(PartialEq, Clone)]
+pub struct DecodeError {
+    // What went wrong, which callers should match on rather than the message.
+    pub error: PngError,
+    // Absolute offset of the byte where the error was found, from the start of the signature.
+    pub offset: u64,
+    // Type and index (from 0, for IHDR) of the enclosing chunk.
+    pub chunk: Option<([u8, ..4], uint)>,
+    // For IDAT errors, the scanline within the pass and the Adam7 pass (0 if
+    // not interlaced), unless all the scanlines were already decoded.
+    pub scanline: Option<(u32, u8)>
+}
+
+impl DecodeError {
+    pub fn new(error: PngError) -> DecodeError {
+        DecodeError {
+            error: error,
+            offset: 0,
+            chunk: None,
+            scanline: None
+        }
+    }
+}
+
+impl fmt::Show for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} (at byte {}", self.error, self.offset));
+        match self.chunk {
+            Some((name, index)) => {
+                try!(write!(f, ", in {} chunk #{}", from_utf8(name).unwrap_or("????"), index))
+            }
+            None => {}
+        }
+        match self.scanline {
+            Some((y, 0)) => try!(write!(f, ", scanline {}", y)),
+            Some((y, pass)) => try!(write!(f, ", scanline {} of Adam7 pass {}", y, pass)),
+            None => {}
+        }
+        write!(f, ")")
+    }
 }
 
 pub enum ImageState<'a> {
     Partial(Option<&'a Image>),
     Complete(Image),
-    Error(String)
+    // The PngError to match on is DecodeError::error, along with where it was found.
+    Error(DecodeError)
 }
 
 static MAGIC: [u8, ..8] = [
@@ -103,10 +407,10 @@ struct Ihdr {
 }
 
 impl Ihdr {
-    fn get_color_type(&self) -> Result<ColorType, String> {
+    fn get_color_type(&self) -> Result<ColorType, PngError> {
         let bits = self.bits;
-        let invalid = |name| Err(format!("invalid bit depth {} for color type {} ({:s})",
-                                         bits, self.color_type, name));
+        let invalid = |name| Err(Malformed(format!("invalid bit depth {} for color type {} ({:s})",
+                                                   bits, self.color_type, name)));
         Ok(match self.color_type {
             0 => match bits {
                 1 => K1,
@@ -138,55 +442,167 @@ impl Ihdr {
                 16 => RGBA16,
                 _ => return invalid("truecolor with alpha")
             },
-            _ => return Err(format!("invalid color type {}", self.color_type))
+            _ => return Err(Malformed(format!("invalid color type {}", self.color_type)))
         })
     }
 
-    fn to_image(&self) -> Result<PartialImage, String> {
+    fn to_info(&self) -> Result<ImageInfo, PngError> {
         let color_type = match self.get_color_type() {
             Ok(c) => c,
             Err(m) => return Err(m)
         };
 
-        let color_decoded = match color_type {
-            K1 | K2 | K4 | K8 | KA8 => KA8,
-            K16 | KA16 | RGB16 | RGBA16 => return Err("unsupported bit depth of 16".to_string()),
-            _ => RGBA8
-        };
-
-        let pixel_bytes = color_decoded.pixel_bits() / 8;
-
-        let pixel_bits_raw = color_type.pixel_bits();
-
         if self.compression_method != 0 {
-            return Err(format!("unknown compression method {}", self.compression_method));
+            return Err(Unsupported(format!("unknown compression method {}", self.compression_method)));
         }
 
         if self.filter_method != 0 {
-            return Err(format!("unknown filter method {}", self.filter_method));
+            return Err(Unsupported(format!("unknown filter method {}", self.filter_method)));
         }
 
         if self.interlace_method > 1 {
-            return Err(format!("unknown interlace method {}", self.interlace_method));
+            return Err(Unsupported(format!("unknown interlace method {}", self.interlace_method)));
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return Err(Malformed(format!("invalid image size {}x{}", self.width, self.height)));
+        }
+
+        Ok(ImageInfo {
+            width: self.width,
+            height: self.height,
+            bit_depth: self.bits,
+            color_type: color_type,
+            compression_method: self.compression_method,
+            filter_method: self.filter_method,
+            interlace_method: self.interlace_method,
+            has_plte: false,
+            has_trns: false,
+            palette_size: 0,
+            gamma: None
+        })
+    }
+
+    fn to_image(&self, options: &DecoderOptions) -> Result<PartialImage, PngError> {
+        let info = match self.to_info() {
+            Ok(info) => info,
+            Err(m) => return Err(m)
+        };
+        let color_type = info.color_type;
+
+        if options.transformations.is_some() &&
+           (options.output != OutputExpanded || options.layout != LayoutRGBA8 || options.premultiply) {
+            return Err(Unsupported("transformations replace the output, layout and premultiply options".to_string()));
         }
 
+        // Only palette images can be decoded to indices.
+        let output = match options.output {
+            _ if options.transformations.is_some() => OutputNative,
+            OutputIndexed if !color_type.is_palette() => OutputExpanded,
+            output => output
+        };
+
+        let color_decoded = match output {
+            OutputNative => match options.transformations {
+                Some(ref t) => t.output_type(color_type, false),
+                None => color_type
+            },
+            OutputIndexed => Pal8,
+            OutputExpanded => match color_type {
+                K1 | K2 | K4 | K8 | KA8 => KA8,
+                K16 | KA16 => KA16,
+                RGB16 | RGBA16 => RGBA16,
+                _ if options.layout == LayoutRGB8 => RGB8,
+                _ => RGBA8
+            }
+        };
+
+        // Palette and sub-byte grayscale images left as they are by the
+        // transformations are stored like OutputNative does.
+        let transformations = match options.transformations {
+            Some(_) if color_decoded.is_palette() || color_decoded.bit_depth() < 8 => None,
+            ref t => t.clone()
+        };
+
+        // Pixels are decoded as RGBA8 and each byte is moved to its place in
+        // the layout as it's stored, so positions are still RGBA8 ones.
+        let layout = match (output, color_decoded) {
+            (OutputExpanded, RGB8) | (OutputExpanded, RGBA8) => options.layout.offsets(),
+            _ => None
+        };
+
+        // Only 8-bit pixels with alpha are premultiplied.
+        let premultiplied = options.premultiply && output == OutputExpanded && match color_decoded {
+            KA8 | RGBA8 => true,
+            _ => false
+        };
+
+        let pixel_bits = color_decoded.pixel_bits();
+        let pixel_bits_logical = if layout.is_some() { 32 } else { pixel_bits };
+
+        let pixel_bits_raw = color_type.pixel_bits();
+
         let w = self.width as uint;
         let h = self.height as uint;
 
+        // Check the sizes of everything allocated below, without overflowing.
+        // Rows of less than 8 bits per pixel are padded to a whole byte.
+        let limits = &options.limits;
+        let row_bits = w.checked_mul(&pixel_bits).and_then(|n| n.checked_add(&7));
+        let scanline_bits = w.checked_mul(&pixel_bits_raw).and_then(|n| n.checked_add(&7));
+        let pixels_len = match (row_bits, scanline_bits) {
+            (Some(n), Some(_)) => h.checked_mul(&(n / 8)),
+            _ => None
+        };
+        let pixels_len = match pixels_len {
+            Some(n) if n <= limits.max_pixel_bytes => n,
+            _ => return Err(LimitExceeded(format!("{}x{} image is over the limit of {} bytes of pixels",
+                                                  w, h, limits.max_pixel_bytes)))
+        };
+
         let initial_scanline_width = if self.interlace_method == 1 {
             (w + 7) / 8
         } else {
             w
         };
 
+        // Only 8-bit samples map one-to-one onto the expanded pixels, everything
+        // else needs filters to be undone on a copy of the raw scanline.
+        // Premultiplied pixels can't be used to undo filters either.
+        let use_scanline = premultiplied || match (output, color_type) {
+            (OutputExpanded, K8) | (OutputExpanded, KA8) | (OutputExpanded, RGB8) => false,
+            // Alpha is needed to undo the filters, even if it's not stored.
+            (OutputExpanded, RGBA8) => options.layout == LayoutRGB8,
+            _ => true
+        };
+        let (scanline, scanline_prev) = if use_scanline {
+            let scanline_bytes_max = (w * pixel_bits_raw + 7) / 8;
+            (Vec::from_elem(scanline_bytes_max, 0u8), Vec::from_elem(scanline_bytes_max, 0u8))
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
         Ok(PartialImage {
             image: Image {
                 width: self.width,
                 height: self.height,
                 color_type: color_decoded,
-                pixels: Vec::from_elem(w * h * pixel_bytes, 0u8)
+                pixels: Vec::from_elem(pixels_len, 0u8),
+                info: info,
+                palette: None,
+                transparent_color: None,
+                layout: if layout.is_some() { options.layout } else { LayoutRGBA8 },
+                premultiplied: premultiplied,
+                color_info: ColorInfo::new()
             },
             color_type: color_type,
+            output: output,
+            layout: layout,
+            premultiplied: premultiplied,
+            transformations: transformations,
+            trns_alpha: false,
+            gamma_table: None,
+            srgb: None,
             filter: 0,
             interlace: self.interlace_method,
             palette: None,
@@ -194,8 +610,11 @@ impl Ihdr {
             idat_inflate_stream: None,
             x_byte_pos: 0,
             y_byte_pos: 0,
-            scanline_bytes: w * pixel_bytes,
+            scanline_bytes: (w * pixel_bits_logical + 7) / 8,
             scanline_pos: None,
+            use_scanline: use_scanline,
+            scanline: scanline,
+            scanline_prev: scanline_prev,
             pixel_prev: [0, ..4],
             pixel_bytes_raw: (pixel_bits_raw + 7) / 8,
             scanline_bytes_raw: (initial_scanline_width * pixel_bits_raw + 7) / 8
@@ -260,6 +679,16 @@ impl Filter for Paeth {
 struct PartialImage {
     image: Image,
     color_type: ColorType,
+    output: OutputMode,
+    // Offset of R, G, B and A in each pixel, and the pixel size, for layouts other than RGBA8.
+    layout: Option<([u8, ..4], uint)>,
+    premultiplied: bool,
+    transformations: Option<Transformations>,
+    // Whether the transformations add alpha to pixels without it, decided when IDAT starts.
+    trns_alpha: bool,
+    // Built from gAMA, cHRM and sRGB for the transformations when IDAT starts.
+    gamma_table: Option<Vec<u16>>,
+    srgb: Option<SrgbTransform>,
     filter: u8,
     interlace: u8,
     palette: Option<Vec<u8>>,
@@ -269,13 +698,16 @@ struct PartialImage {
     y_byte_pos: uint,
     scanline_bytes: uint,
     scanline_pos: Option<uint>,
+    use_scanline: bool,
+    scanline: Vec<u8>,
+    scanline_prev: Vec<u8>,
     pixel_prev: [u8, ..4],
     pixel_bytes_raw: uint, // FIXME(eddyb) don't waste space.
     scanline_bytes_raw: uint
 }
 
 impl PartialImage {
-    fn update_idat(&mut self, mut data: &[u8]) -> Result<(), String> {
+    fn update_idat(&mut self, mut data: &[u8]) -> Result<(), PngError> {
         let mut scanline_pos = self.scanline_pos;
         let mut filter = self.filter;
 
@@ -283,6 +715,9 @@ impl PartialImage {
             let mut i = match scanline_pos {
                 Some(pos) => pos,
                 None => {
+                    if self.y_byte_pos >= self.image_bytes() {
+                        return Err(Malformed("extra data after the last scanline".to_string()));
+                    }
                     filter = data[0];
                     data = data.slice_from(1);
                     0
@@ -291,68 +726,79 @@ impl PartialImage {
 
             let line = data.slice_to(min(self.scanline_bytes_raw - i, data.len()));
 
-            match filter {
-                0 => self.update_scanline(line, NoFilter),
-                1 => {
-                    if i < self.pixel_bytes_raw {
-                        let noop = min(self.pixel_bytes_raw - i, line.len());
-                        self.update_scanline(line.slice_to(noop), NoFilter);
-                        self.update_scanline(line.slice_from(noop), Sub); // FIXME(eddyb) DRY Sub vvv
-                    } else {
-                        self.update_scanline(line, Sub);
-                    }
-                }
-                2 => {
-                    let (_, _, _, dy) = self.interlace_params();
-                    if self.y_byte_pos < dy * self.scanline_bytes {
-                        self.update_scanline(line, NoFilter);
-                    } else {
-                        self.update_scanline(line, Up);
-                    }
+            if self.use_scanline {
+                match filter {
+                    0 => self.unfilter_scanline(i, line, NoFilter),
+                    1 => self.unfilter_scanline(i, line, Sub),
+                    2 => self.unfilter_scanline(i, line, Up),
+                    3 => self.unfilter_scanline(i, line, Average),
+                    4 => self.unfilter_scanline(i, line, Paeth),
+                    _ => return Err(Malformed(format!("unknown filter `{}`", filter)))
                 }
-                3 => {
-                    let (_, _, _, dy) = self.interlace_params();
-                    if self.y_byte_pos < dy * self.scanline_bytes {
-                        // FIXME(eddyb) maybe it's forbidden to have Average for the first scanline?
+            } else {
+                match filter {
+                    0 => self.update_scanline(line, NoFilter),
+                    1 => {
                         if i < self.pixel_bytes_raw {
                             let noop = min(self.pixel_bytes_raw - i, line.len());
                             self.update_scanline(line.slice_to(noop), NoFilter);
-                            //self.update_scanline(line.slice_from(noop), Half(Sub)); // FIXME(eddyb) DRY Half(Sub) vvv
+                            self.update_scanline(line.slice_from(noop), Sub); // FIXME(eddyb) DRY Sub vvv
                         } else {
-                            //self.update_scanline(line, Half(Sub));
+                            self.update_scanline(line, Sub);
                         }
-                    } else {
-                        if i < self.pixel_bytes_raw {
-                            let noop = min(self.pixel_bytes_raw - i, line.len());
-                            //self.update_scanline(line.slice_to(noop), Half(Up));
-                            self.update_scanline(line.slice_from(noop), Average); // FIXME(eddyb) DRY Average vvv
+                    }
+                    2 => {
+                        let (_, _, _, dy) = self.interlace_params();
+                        if self.y_byte_pos < dy * self.scanline_bytes {
+                            self.update_scanline(line, NoFilter);
                         } else {
-                            self.update_scanline(line, Average);
+                            self.update_scanline(line, Up);
                         }
                     }
-                }
-                4 => {
-                    let (_, _, _, dy) = self.interlace_params();
-                    if self.y_byte_pos < dy * self.scanline_bytes {
-                        // FIXME(eddyb) maybe it's forbidden to have Paeth for the first scanline?
-                        if i < self.pixel_bytes_raw {
-                            let noop = min(self.pixel_bytes_raw - i, line.len());
-                            self.update_scanline(line.slice_to(noop), NoFilter);
-                            self.update_scanline(line.slice_from(noop), Sub); // FIXME(eddyb) DRY Sub vvv
+                    3 => {
+                        let (_, _, _, dy) = self.interlace_params();
+                        if self.y_byte_pos < dy * self.scanline_bytes {
+                            // FIXME(eddyb) maybe it's forbidden to have Average for the first scanline?
+                            if i < self.pixel_bytes_raw {
+                                let noop = min(self.pixel_bytes_raw - i, line.len());
+                                self.update_scanline(line.slice_to(noop), NoFilter);
+                                //self.update_scanline(line.slice_from(noop), Half(Sub)); // FIXME(eddyb) DRY Half(Sub) vvv
+                            } else {
+                                //self.update_scanline(line, Half(Sub));
+                            }
                         } else {
-                            self.update_scanline(line, Sub);
+                            if i < self.pixel_bytes_raw {
+                                let noop = min(self.pixel_bytes_raw - i, line.len());
+                                //self.update_scanline(line.slice_to(noop), Half(Up));
+                                self.update_scanline(line.slice_from(noop), Average); // FIXME(eddyb) DRY Average vvv
+                            } else {
+                                self.update_scanline(line, Average);
+                            }
                         }
-                    } else {
-                        if i < self.pixel_bytes_raw {
-                            let noop = min(self.pixel_bytes_raw - i, line.len());
-                            self.update_scanline(line.slice_to(noop), Up);
-                            self.update_scanline(line.slice_from(noop), Paeth); // FIXME(eddyb) DRY Paeth vvv
+                    }
+                    4 => {
+                        let (_, _, _, dy) = self.interlace_params();
+                        if self.y_byte_pos < dy * self.scanline_bytes {
+                            // FIXME(eddyb) maybe it's forbidden to have Paeth for the first scanline?
+                            if i < self.pixel_bytes_raw {
+                                let noop = min(self.pixel_bytes_raw - i, line.len());
+                                self.update_scanline(line.slice_to(noop), NoFilter);
+                                self.update_scanline(line.slice_from(noop), Sub); // FIXME(eddyb) DRY Sub vvv
+                            } else {
+                                self.update_scanline(line, Sub);
+                            }
                         } else {
-                            self.update_scanline(line, Paeth);
+                            if i < self.pixel_bytes_raw {
+                                let noop = min(self.pixel_bytes_raw - i, line.len());
+                                self.update_scanline(line.slice_to(noop), Up);
+                                self.update_scanline(line.slice_from(noop), Paeth); // FIXME(eddyb) DRY Paeth vvv
+                            } else {
+                                self.update_scanline(line, Paeth);
+                            }
                         }
                     }
+                    _ => return Err(Malformed(format!("unknown filter `{}`", filter)))
                 }
-                _ => return Err(format!("unknown filter `{}`", filter))
             }
 
             /*match filter {
@@ -425,6 +871,12 @@ impl PartialImage {
             scanline_pos = if i < self.scanline_bytes_raw {
                 Some(i)
             } else {
+                if self.use_scanline {
+                    match self.store_scanline() {
+                        Ok(()) => self.next_scanline(),
+                        Err(m) => return Err(m)
+                    }
+                }
                 None
             };
         }
@@ -460,131 +912,457 @@ impl PartialImage {
         }
     }
 
-    fn update_scanline<F: Filter>(&mut self, data: &[u8], f: F) {
-        // HACK(eddyb) specialize update_scanline_with_dx for the best cases.
-        // See interlace_params for more information.
-        match self.interlace {
-            0 | 7 => self.update_scanline_with_dx::<[u8, ..1], F>(data, f),
-            1 | 2 => self.update_scanline_with_dx::<[u8, ..8], F>(data, f),
-            3 | 4 => self.update_scanline_with_dx::<[u8, ..4], F>(data, f),
-            5 | 6 => self.update_scanline_with_dx::<[u8, ..2], F>(data, f),
-            _ => fail!("unreacheable (interlace step)")
+    // Number of pixels in each scanline of the current interlace pass.
+    fn pass_width(&self) -> uint {
+        let (x0, _, dx, _) = self.interlace_params();
+        let w = self.image.width as uint;
+        if w > x0 {
+            (w - x0 + dx - 1) / dx
+        } else {
+            0
         }
     }
 
-    fn update_scanline_with_dx<DX, F: Filter>(&mut self, mut data: &[u8], mut f: F) {
-        // HACK extract dx from the size of DX = [u8, ..dx].
-        let dx = ::std::mem::size_of::<DX>();
-        let (x0, _, _, dy) = self.interlace_params();
-
-        let mut i = self.y_byte_pos + self.x_byte_pos;
-        let next_line = self.y_byte_pos + self.scanline_bytes;
-
-        {
-            let pixels = self.image.pixels.as_mut_slice();
+    // Bytes per pixel, as RGBA8 for layouts other than RGBA8.
+    fn pixel_bytes(&self) -> uint {
+        match self.layout {
+            Some(_) => 4,
+            None => self.image.color_type.pixel_bits() / 8
+        }
+    }
 
-            macro_rules! filter (($x:expr, $pixel_bytes:expr) => ({
-                // HACK(eddyb) this requires the filter to not deref invalid references.
-                let (a, b, c): (&u8, &u8, &u8) = unsafe {(
-                    mem::transmute(pixels.unsafe_ref(i - dx * $pixel_bytes)),
-                    mem::transmute(pixels.unsafe_ref(i - dy * self.scanline_bytes)),
-                    mem::transmute(pixels.unsafe_ref(i - dx * $pixel_bytes - dy * self.scanline_bytes))
-                )};
-                f.apply($x, a, b, c)
-            }))
+    // Size of the pixels, counted in the same way as y_byte_pos.
+    fn image_bytes(&self) -> uint {
+        self.scanline_bytes * self.image.height as uint
+    }
 
-            // Alpha for a RGB8 color (transparent if equal to tRNS, opaque otherwise).
-            macro_rules! trns_rgb8 (($r:expr, $g:expr, $b:expr) => ({
-                match self.transparent_color {
-                    Some([tr, tg, tb]) if tr == $r as u16
-                                       && tg == $g as u16
-                                       && tb == $b as u16 => 0x00,
-                    _ => 0xff
-                }
-            }))
+    // Scanline within the current pass, and the pass (0 if not interlaced),
+    // or None after the last scanline.
+    fn scanline_position(&self) -> Option<(u32, u8)> {
+        if self.y_byte_pos >= self.image_bytes() {
+            return None;
+        }
+        let (_, y0, _, dy) = self.interlace_params();
+        let y = self.y_byte_pos / self.scanline_bytes;
+        Some((((y - y0) / dy) as u32, self.interlace))
+    }
 
-            // One byte of RGBA or KA (just one store, not updating i).
-            macro_rules! pixel_byte_store (($byte:expr, $offset:expr) => ({
-                // HACK(eddyb) avoid bound checks, LLVM can't optimize this.
-                let byte = $byte;
-                // pixels[$offset] = byte;
-                unsafe {
-                    pixels.unsafe_set($offset, byte);
-                }
-            }))
+    // Whether all the scanlines were stored and the ZLIB stream has ended.
+    fn is_complete(&self) -> bool {
+        let done = self.idat_inflate_stream.as_ref().map_or(false, |stream| stream.is_done());
+        done && (self.interlace == 0 || self.interlace == 7) && self.y_byte_pos >= self.image_bytes()
+    }
 
-            // Grayscale + Alpha.
-            macro_rules! pixel_ka (($k:expr, $alpha:expr) => ({
-                // FIXME(eddyb) Why is this slower?
-                /*pixel_byte_store!($k, i);
-                pixel_byte_store!($alpha, i + 1);
-                i += dx * 2;*/
-                pixel_byte_store!($k, i); i += 1;
-                pixel_byte_store!($alpha, i); i += 1;
-                i += (dx - 1) * 2;
-            }))
+    fn next_scanline(&mut self) {
+        let pixel_bytes = self.pixel_bytes();
+        let (x0, _, _, dy) = self.interlace_params();
 
-            // Grayscale.
-            macro_rules! pixel_k (($k:expr, $multiplier:expr) => ({
-                let k = $k;
-                let alpha = match self.transparent_color {
-                    Some([tk, ..]) if tk == k as u16 => 0x00,
-                    _ => 0xff
-                };
-                pixel_ka!(k * $multiplier, alpha);
-            }))
-            // Palette.
-            /*let pixel_pal = |j: u8| {
-                let palette = self.palette.as_ref().unwrap();
-                let j = j as uint * 4;
-                pixel_byte_store!(palette[j], i);
-                pixel_byte_store!(palette[j + 1], i + 1);
-                pixel_byte_store!(palette[j + 2], i + 2);
-                pixel_byte_store!(palette[j + 3], i + 3);
-                i += dx * 4;
-            };*/
-            // One byte of RGBA or KA.
-            macro_rules! pixel_byte (($byte:expr, $pixel_bytes:expr) => ({
-                pixel_byte_store!($byte, i);
+        self.x_byte_pos = x0 * pixel_bytes;
+        self.y_byte_pos += dy * self.scanline_bytes;
+        mem::swap(&mut self.scanline, &mut self.scanline_prev);
 
-                i += 1;
+        if self.y_byte_pos >= self.image_bytes() {
+            self.next_pass();
+        }
+    }
 
-                if i % $pixel_bytes == 0 {
-                    i += (dx - 1) * $pixel_bytes;
+    fn next_pass(&mut self) {
+        let pixel_bytes = self.pixel_bytes();
+        let w = self.image.width as uint;
+        let h = self.image.height as uint;
+        loop {
+            match self.interlace {
+                0 | 7 => {
+                    // FIXME(eddyb) free all temporary structures.
+                    self.image.palette = self.palette.take().map(|rgba| Palette::from_rgba(rgba.as_slice()));
+                    self.image.transparent_color = self.transparent_color;
+                    self.idat_inflate_stream = None;
+                    return;
                 }
-            }))
+                _ => {
+                    self.interlace += 1;
+                    let (x0, y0, _, _) = self.interlace_params();
 
-            match self.color_type {
-                // FIXME(eddyb) enable this whenever it doesn't slow everything down anymore.
-                /*K1 | K2 | K4 => for &x in data.map(|&x| filter!(x, 2)).iter() {
-                    let bits = self.color_type.pixel_bits();
-                    let multiplier = match bits {
-                        2 => 0x55,
-                        4 => 0x11,
-                        _ => 0xff
-                    };
-                    for bit in range_step_inclusive(8 - bits, 0, bits) {
-                        pixel_k((x >> bit) & ((1 << bits) - 1), multiplier);
-                        if i > next_line { // TODO(eddyb) optimize (check only for last byte).
-                            break;
+                    // Passes without any pixels have no scanlines at all.
+                    if x0 < w && y0 < h {
+                        self.x_byte_pos = x0 * pixel_bytes;
+                        self.y_byte_pos = y0 * self.scanline_bytes;
+                        self.scanline_bytes_raw = (self.pass_width() * self.color_type.pixel_bits() + 7) / 8;
+                        for x in self.scanline_prev.mut_iter() {
+                            *x = 0;
                         }
+                        return;
                     }
-                },*/
-                K8 => for &x in data.iter() {
-                    pixel_k!(filter!(x, 2), 0x01);
-                },
-                KA8 => {
-                    // Process the first few bytes until i is 2-aligned.
-                    if i % 2 == 1 {
-                        pixel_byte!(filter!(data[0], 2), 2);
-                        data = data.slice_from(1);
-                    }
-                    if data.len() >= 2 {
-                        assert!(i % 2 == 0);
-                        // HACK(eddyb) Works better than a chunks iterator.
-                        for j in range_step_inclusive(0, data.len() - 2, 2) {
-                            let (k, a) = unsafe {(
-                                *data.unsafe_ref(j),
+                }
+            }
+        }
+    }
+
+    // Precompute the color conversions of the transformations, once the
+    // chunks they depend on can no longer change.
+    fn build_tables(&mut self, file_gamma: Option<u32>) {
+        let (display_gamma, to_srgb) = match self.transformations {
+            Some(ref t) => (t.display_gamma, t.to_srgb),
+            None => (None, false)
+        };
+        let sixteen = self.color_type.bit_depth() == 16;
+        self.gamma_table = match (file_gamma, display_gamma) {
+            (Some(file), Some(display)) => Some(gamma_table(file, display, sixteen)),
+            _ => None
+        };
+        self.srgb = if to_srgb {
+            Some(SrgbTransform::from_png(&self.image.color_info, file_gamma, sixteen,
+                                         self.color_type.is_gray()))
+        } else {
+            None
+        };
+    }
+
+    // Palette and sub-byte grayscale images expanded by the transformations
+    // only get alpha if there's a tRNS chunk, so the pixels are reallocated
+    // with alpha when IDAT starts if needed.
+    fn resolve_trns_alpha(&mut self, has_trns: bool, max_pixel_bytes: uint) -> Result<(), PngError> {
+        let (trns_alpha, color_type) = match self.transformations {
+            Some(ref t) => (t.trns_alpha(self.color_type, has_trns),
+                            t.output_type(self.color_type, has_trns)),
+            None => return Ok(())
+        };
+        self.trns_alpha = trns_alpha;
+        if color_type == self.image.color_type {
+            return Ok(());
+        }
+        let (w, h) = (self.image.width as uint, self.image.height as uint);
+        let scanline_bytes = w.checked_mul(&(color_type.pixel_bits() / 8));
+        let pixels_len = match scanline_bytes.and_then(|n| n.checked_mul(&h)) {
+            Some(n) if n <= max_pixel_bytes => n,
+            _ => return Err(LimitExceeded(format!("{}x{} image is over the limit of {} bytes of pixels",
+                                                  w, h, max_pixel_bytes)))
+        };
+        self.image.color_type = color_type;
+        self.image.pixels = Vec::from_elem(pixels_len, 0u8);
+        self.scanline_bytes = scanline_bytes.unwrap();
+        Ok(())
+    }
+
+    // Undo the filter on a copy of the raw scanline, starting from byte i.
+    // The previous scanline is all zeroes for the first one in each pass.
+    fn unfilter_scanline<F: Filter>(&mut self, mut i: uint, data: &[u8], mut f: F) {
+        let pixel_bytes = self.pixel_bytes_raw;
+        let prev = self.scanline_prev.as_slice();
+        let scanline = self.scanline.as_mut_slice();
+        for &x in data.iter() {
+            let (a, c) = if i < pixel_bytes {
+                (0, 0)
+            } else {
+                (scanline[i - pixel_bytes], prev[i - pixel_bytes])
+            };
+            scanline[i] = f.apply(x, &a, &prev[i], &c);
+            i += 1;
+        }
+    }
+
+    // Expand a complete (unfiltered) raw scanline into the image pixels.
+    fn store_scanline(&mut self) -> Result<(), PngError> {
+        let (x0, _, dx, _) = self.interlace_params();
+        let width = self.pass_width();
+        let bits = self.color_type.pixel_bits();
+        let pixel_bytes = self.pixel_bytes();
+
+        let scanline = self.scanline.slice_to(self.scanline_bytes_raw);
+        let pixels = self.image.pixels.as_mut_slice();
+
+        match self.transformations {
+            Some(ref t) => {
+                let sixteen = self.color_type.bit_depth() == 16;
+                let palette = self.palette.as_ref().map(|p| p.as_slice());
+                let gamma_table = self.gamma_table.as_ref().map(|t| t.as_slice());
+                let srgb = self.srgb.as_ref();
+                let y = self.y_byte_pos / self.scanline_bytes;
+                let (gray, out_bits) = match self.image.color_type {
+                    K8 | KA8 => (true, 8),
+                    K16 | KA16 => (true, 16),
+                    c => (false, c.bit_depth())
+                };
+                let mut i = self.y_byte_pos + x0 * pixel_bytes;
+                for x in range(0, width) {
+                    let (color, alpha) = match read_pixel(self.color_type, scanline, x, palette,
+                                                          self.trns_alpha, self.transparent_color) {
+                        Ok((color, alpha)) => {
+                            t.apply(sixteen, gamma_table, srgb, color, alpha, x0 + x * dx, y)
+                        }
+                        Err(m) => return Err(m)
+                    };
+                    let samples = color.slice_to(if gray { 1 } else { 3 });
+                    let mut j = i;
+                    for &v in samples.iter().chain(alpha.iter()) {
+                        if out_bits == 16 {
+                            pixels[j] = (v >> 8) as u8;
+                            pixels[j + 1] = v as u8;
+                            j += 2;
+                        } else {
+                            pixels[j] = v as u8;
+                            j += 1;
+                        }
+                    }
+                    i += dx * pixel_bytes;
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
+        if self.output == OutputNative {
+            let row = self.y_byte_pos;
+            if dx == 1 {
+                pixels.mut_slice(row, row + scanline.len()).copy_from(scanline);
+            } else if bits >= 8 {
+                let n = bits / 8;
+                for x in range(0, width) {
+                    let i = row + (x0 + x * dx) * n;
+                    pixels.mut_slice(i, i + n).copy_from(scanline.slice(x * n, x * n + n));
+                }
+            } else {
+                let mask = ((1 << bits) - 1) as u8;
+                for x in range(0, width) {
+                    let bit = (x0 + x * dx) * bits;
+                    let shift = 8 - bits - bit % 8;
+                    let i = row + bit / 8;
+                    pixels[i] = (pixels[i] & !(mask << shift)) | (unpack_sample(scanline, x, bits) << shift);
+                }
+            }
+            return Ok(());
+        }
+
+        let mut i = self.y_byte_pos + x0 * pixel_bytes;
+
+        // Only the first `width` samples are used, any padding bits
+        // at the end of the scanline are ignored.
+        match self.color_type {
+            K1 | K2 | K4 => {
+                let multiplier = match bits {
+                    1 => 0xff,
+                    2 => 0x55,
+                    _ => 0x11
+                };
+                for x in range(0, width) {
+                    let k = unpack_sample(scanline, x, bits);
+                    let alpha = match self.transparent_color {
+                        Some([tk, ..]) if tk == k as u16 => 0x00,
+                        _ => 0xff
+                    };
+                    store_ka(pixels, self.premultiplied, i, k * multiplier, alpha);
+                    i += dx * 2;
+                }
+            }
+            K8 | KA8 => {
+                let n = self.pixel_bytes_raw;
+                for x in range(0, width) {
+                    let k = scanline[x * n];
+                    let alpha = match self.transparent_color {
+                        _ if n == 2 => scanline[x * 2 + 1],
+                        Some([tk, ..]) if tk == k as u16 => 0x00,
+                        _ => 0xff
+                    };
+                    store_ka(pixels, self.premultiplied, i, k, alpha);
+                    i += dx * 2;
+                }
+            }
+            K16 => for x in range(0, width) {
+                let (hi, lo) = (scanline[x * 2], scanline[x * 2 + 1]);
+                let k = (hi as u16 << 8) | lo as u16;
+                let alpha = match self.transparent_color {
+                    Some([tk, ..]) if tk == k => 0x00,
+                    _ => 0xff
+                };
+                pixels[i] = hi;
+                pixels[i + 1] = lo;
+                pixels[i + 2] = alpha;
+                pixels[i + 3] = alpha;
+                i += dx * 4;
+            },
+            RGB16 => for x in range(0, width) {
+                let rgb = scanline.slice(x * 6, x * 6 + 6);
+                let sample = |j: uint| (rgb[j * 2] as u16 << 8) | rgb[j * 2 + 1] as u16;
+                let alpha = match self.transparent_color {
+                    Some([tr, tg, tb]) if tr == sample(0)
+                                       && tg == sample(1)
+                                       && tb == sample(2) => 0x00,
+                    _ => 0xff
+                };
+                pixels.mut_slice(i, i + 6).copy_from(rgb);
+                pixels[i + 6] = alpha;
+                pixels[i + 7] = alpha;
+                i += dx * 8;
+            },
+            KA16 | RGBA16 => {
+                let n = pixel_bytes;
+                for x in range(0, width) {
+                    pixels.mut_slice(i, i + n).copy_from(scanline.slice(x * n, x * n + n));
+                    i += dx * n;
+                }
+            }
+            Pal1 | Pal2 | Pal4 | Pal8 => {
+                let palette = self.palette.as_ref().unwrap().as_slice();
+                let indexed = self.output == OutputIndexed;
+                for x in range(0, width) {
+                    let index = unpack_sample(scanline, x, bits);
+                    let j = index as uint * 4;
+                    if j >= palette.len() {
+                        return Err(Malformed(format!("palette index {} out of range, PLTE has {} entries",
+                                                     index, palette.len() / 4)));
+                    }
+                    if indexed {
+                        pixels[i] = index;
+                        i += dx;
+                    } else {
+                        store_rgba(pixels, self.layout, self.premultiplied, i, palette.slice(j, j + 4));
+                        i += dx * 4;
+                    }
+                }
+            }
+            RGB8 | RGBA8 => {
+                let n = self.pixel_bytes_raw;
+                for x in range(0, width) {
+                    let p = scanline.slice(x * n, x * n + n);
+                    let alpha = match self.transparent_color {
+                        _ if n == 4 => p[3],
+                        Some([tr, tg, tb]) if tr == p[0] as u16
+                                           && tg == p[1] as u16
+                                           && tb == p[2] as u16 => 0x00,
+                        _ => 0xff
+                    };
+                    store_rgba(pixels, self.layout, self.premultiplied, i, [p[0], p[1], p[2], alpha]);
+                    i += dx * 4;
+                }
+            }
+            _ => fail!("unreacheable (scanline color type)")
+        }
+
+        Ok(())
+    }
+
+    fn update_scanline<F: Filter>(&mut self, data: &[u8], f: F) {
+        // HACK(eddyb) specialize update_scanline_with_dx for the best cases.
+        // See interlace_params for more information.
+        match self.interlace {
+            0 | 7 => self.update_scanline_with_dx::<[u8, ..1], F>(data, f),
+            1 | 2 => self.update_scanline_with_dx::<[u8, ..8], F>(data, f),
+            3 | 4 => self.update_scanline_with_dx::<[u8, ..4], F>(data, f),
+            5 | 6 => self.update_scanline_with_dx::<[u8, ..2], F>(data, f),
+            _ => fail!("unreacheable (interlace step)")
+        }
+    }
+
+    fn update_scanline_with_dx<DX, F: Filter>(&mut self, mut data: &[u8], mut f: F) {
+        // HACK extract dx from the size of DX = [u8, ..dx].
+        let dx = ::std::mem::size_of::<DX>();
+        let (_, _, _, dy) = self.interlace_params();
+
+        let mut i = self.y_byte_pos + self.x_byte_pos;
+        let next_line = self.y_byte_pos + self.scanline_bytes;
+        let layout = self.layout;
+
+        {
+            let pixels = self.image.pixels.as_mut_slice();
+
+            // Where an RGBA8 byte position is in the pixels, with the layout applied.
+            macro_rules! layout_pos (($i:expr) => ({
+                let i = $i;
+                match layout {
+                    None => i,
+                    Some((offsets, bytes)) => (i >> 2) * bytes + offsets[i & 3] as uint
+                }
+            }))
+
+            macro_rules! filter (($x:expr, $pixel_bytes:expr) => ({
+                // HACK(eddyb) this requires the filter to not deref invalid references.
+                let (a, b, c): (&u8, &u8, &u8) = unsafe {(
+                    mem::transmute(pixels.unsafe_ref(layout_pos!(i - dx * $pixel_bytes))),
+                    mem::transmute(pixels.unsafe_ref(layout_pos!(i - dy * self.scanline_bytes))),
+                    mem::transmute(pixels.unsafe_ref(layout_pos!(i - dx * $pixel_bytes - dy * self.scanline_bytes)))
+                )};
+                f.apply($x, a, b, c)
+            }))
+
+            // Alpha for a RGB8 color (transparent if equal to tRNS, opaque otherwise).
+            macro_rules! trns_rgb8 (($r:expr, $g:expr, $b:expr) => ({
+                match self.transparent_color {
+                    Some([tr, tg, tb]) if tr == $r as u16
+                                       && tg == $g as u16
+                                       && tb == $b as u16 => 0x00,
+                    _ => 0xff
+                }
+            }))
+
+            // One byte of RGBA or KA (just one store, not updating i).
+            // Bytes the layout doesn't have (alpha for RGB8) are dropped.
+            macro_rules! pixel_byte_store (($byte:expr, $offset:expr) => ({
+                // HACK(eddyb) avoid bound checks, LLVM can't optimize this.
+                let byte = $byte;
+                let offset = $offset;
+                match layout {
+                    // pixels[offset] = byte;
+                    None => unsafe {
+                        pixels.unsafe_set(offset, byte);
+                    },
+                    Some((offsets, bytes)) => {
+                        let channel = offsets[offset & 3];
+                        if channel != NO_CHANNEL {
+                            unsafe {
+                                pixels.unsafe_set((offset >> 2) * bytes + channel as uint, byte);
+                            }
+                        }
+                    }
+                }
+            }))
+
+            // Grayscale + Alpha.
+            macro_rules! pixel_ka (($k:expr, $alpha:expr) => ({
+                // FIXME(eddyb) Why is this slower?
+                /*pixel_byte_store!($k, i);
+                pixel_byte_store!($alpha, i + 1);
+                i += dx * 2;*/
+                pixel_byte_store!($k, i); i += 1;
+                pixel_byte_store!($alpha, i); i += 1;
+                i += (dx - 1) * 2;
+            }))
+
+            // Grayscale.
+            macro_rules! pixel_k (($k:expr, $multiplier:expr) => ({
+                let k = $k;
+                let alpha = match self.transparent_color {
+                    Some([tk, ..]) if tk == k as u16 => 0x00,
+                    _ => 0xff
+                };
+                pixel_ka!(k * $multiplier, alpha);
+            }))
+            // One byte of RGBA or KA.
+            macro_rules! pixel_byte (($byte:expr, $pixel_bytes:expr) => ({
+                pixel_byte_store!($byte, i);
+
+                i += 1;
+
+                if i % $pixel_bytes == 0 {
+                    i += (dx - 1) * $pixel_bytes;
+                }
+            }))
+
+            match self.color_type {
+                K8 => for &x in data.iter() {
+                    pixel_k!(filter!(x, 2), 0x01);
+                },
+                KA8 => {
+                    // Process the first few bytes until i is 2-aligned.
+                    if i % 2 == 1 {
+                        pixel_byte!(filter!(data[0], 2), 2);
+                        data = data.slice_from(1);
+                    }
+                    if data.len() >= 2 {
+                        assert!(i % 2 == 0);
+                        // HACK(eddyb) Works better than a chunks iterator.
+                        for j in range_step_inclusive(0, data.len() - 2, 2) {
+                            let (k, a) = unsafe {(
+                                *data.unsafe_ref(j),
                                 *data.unsafe_ref(j + 1)
                             )};
                             // FIXME(eddyb) Why is this slower?
@@ -601,20 +1379,6 @@ impl PartialImage {
                         pixel_byte!(filter!(data[data.len() - 1], 2), 2);
                     }
                 }
-                // FIXME(eddyb) enable this whenever it doesn't slow everything down anymore.
-                /*Pal1 | Pal2 | Pal4 => for &x in data.iter() {
-                    let bits = self.color_type.pixel_bits();
-                    for bit in range_step_inclusive(8 - bits, 0, bits) {
-                        pixel_pal!((x >> bit) & ((1 << bits) - 1));
-                        if i > next_line { // TODO(eddyb) optimize (check only for last byte).
-                            break;
-                        }
-                    }
-                },*/
-                // FIXME(eddyb) enable this when scanline-preserving filters work.
-                /*Pal8 => for &x in data.iter() {
-                    pixel_pal!(x);
-                },*/
                 RGB8 => {
                     // Process the first few bytes until i is 4-aligned.
                     while data.len() > 0 {
@@ -622,7 +1386,9 @@ impl PartialImage {
                         data = data.slice_from(1);
 
                         if i % 4 == 3 {
-                            pixel_byte!(trns_rgb8!(pixels[i - 3], pixels[i - 2], pixels[i - 1]), 4);
+                            pixel_byte!(trns_rgb8!(pixels[layout_pos!(i - 3)],
+                                                  pixels[layout_pos!(i - 2)],
+                                                  pixels[layout_pos!(i - 1)]), 4);
                             break;
                         }
                     }
@@ -712,36 +1478,157 @@ impl PartialImage {
                         }
                     }
                 }
-                _ => fail!("unreacheable (TODO implement more bit depths)")
+                _ => fail!("unreacheable (scanline color type)")
             }
         }
 
         if i < next_line {
             self.x_byte_pos = i - self.y_byte_pos;
         } else {
-            let pixel_bytes = self.image.color_type.pixel_bits() / 8;
-
-            self.x_byte_pos = x0 * pixel_bytes;
-            self.y_byte_pos += dy * self.scanline_bytes;
-            if self.y_byte_pos >= self.image.pixels.len() {
-                match self.interlace {
-                    0 | 7 => {
-                        // FIXME(eddyb) free all temporary structures.
-                        self.palette = None;
-                        self.idat_inflate_stream = None;
-                    }
-                    _ => {
-                        self.interlace += 1;
-                        let (x0, y0, _, _) = self.interlace_params();
-                        self.x_byte_pos = x0 * pixel_bytes;
-                        self.y_byte_pos = y0 * pixel_bytes;
-                    }
+            self.next_scanline();
+        }
+    }
+}
+
+// A color sample multiplied by alpha, rounded to the nearest.
+fn premultiply_sample(c: u8, a: u8) -> u8 {
+    let x = c as u16 * a as u16 + 128;
+    ((x + (x >> 8)) >> 8) as u8
+}
+
+// Store a KA8 pixel at byte i of the pixels.
+fn store_ka(pixels: &mut [u8], premultiplied: bool, i: uint, k: u8, alpha: u8) {
+    pixels[i] = if premultiplied { premultiply_sample(k, alpha) } else { k };
+    pixels[i + 1] = alpha;
+}
+
+// Store an RGBA8 pixel at byte i of the pixels, counted as RGBA8, in the given layout.
+fn store_rgba(pixels: &mut [u8], layout: Option<([u8, ..4], uint)>, premultiplied: bool,
+              i: uint, rgba: &[u8]) {
+    let premultiplied_rgba;
+    let rgba = if premultiplied {
+        let a = rgba[3];
+        premultiplied_rgba = [premultiply_sample(rgba[0], a), premultiply_sample(rgba[1], a),
+                              premultiply_sample(rgba[2], a), a];
+        premultiplied_rgba.as_slice()
+    } else {
+        rgba
+    };
+    match layout {
+        None => {
+            pixels.mut_slice(i, i + 4).copy_from(rgba);
+        }
+        Some((offsets, bytes)) => {
+            let base = i / 4 * bytes;
+            for (&offset, &x) in offsets.iter().zip(rgba.iter()) {
+                if offset != NO_CHANNEL {
+                    pixels[base + offset as uint] = x;
                 }
             }
         }
     }
 }
 
+// The x-th pixel of an unfiltered scanline, as RGB (gray is repeated) and
+// alpha, with sub-byte gray levels scaled to 8 bits. with_alpha adds alpha to
+// palette, grayscale and RGB images, from the palette or tRNS.
+fn read_pixel(color_type: ColorType, scanline: &[u8], x: uint, palette: Option<&[u8]>,
+              with_alpha: bool, trns: Option<[u16, ..3]>) -> Result<([u16, ..3], Option<u16>), PngError> {
+    let bits = color_type.bit_depth();
+    let max = if bits == 16 { 0xffff } else { 0xff };
+    let sample = |j: uint| -> u16 {
+        if bits == 16 {
+            (scanline[j * 2] as u16 << 8) | scanline[j * 2 + 1] as u16
+        } else {
+            scanline[j] as u16
+        }
+    };
+    let trns_alpha = |color: [u16, ..3]| -> Option<u16> {
+        match trns {
+            _ if !with_alpha => None,
+            Some([tr, tg, tb]) if tr == color[0] && tg == color[1] && tb == color[2] => Some(0),
+            _ => Some(max)
+        }
+    };
+    Ok(match color_type {
+        K1 | K2 | K4 => {
+            let k = unpack_sample(scanline, x, bits) as u16;
+            let multiplier = match bits {
+                1 => 0xff,
+                2 => 0x55,
+                _ => 0x11
+            };
+            ([k * multiplier, ..3], trns_alpha([k, ..3]))
+        }
+        K8 | K16 => {
+            let k = sample(x);
+            ([k, ..3], trns_alpha([k, ..3]))
+        }
+        KA8 | KA16 => ([sample(x * 2), ..3], Some(sample(x * 2 + 1))),
+        RGB8 | RGB16 => {
+            let color = [sample(x * 3), sample(x * 3 + 1), sample(x * 3 + 2)];
+            (color, trns_alpha(color))
+        }
+        RGBA8 | RGBA16 => ([sample(x * 4), sample(x * 4 + 1), sample(x * 4 + 2)], Some(sample(x * 4 + 3))),
+        Pal1 | Pal2 | Pal4 | Pal8 => {
+            let palette = palette.unwrap();
+            let index = unpack_sample(scanline, x, bits);
+            let j = index as uint * 4;
+            if j >= palette.len() {
+                return Err(Malformed(format!("palette index {} out of range, PLTE has {} entries",
+                                             index, palette.len() / 4)));
+            }
+            let rgba = palette.slice(j, j + 4);
+            let alpha = if with_alpha { Some(rgba[3] as u16) } else { None };
+            ([rgba[0] as u16, rgba[1] as u16, rgba[2] as u16], alpha)
+        }
+        _ => fail!("unreacheable (scanline color type)")
+    })
+}
+
+// Samples encoded with the file gamma (as in gAMA) to samples for a display
+// gamma (e.g. 220000), both times 100000, for 8-bit or 16-bit samples.
+fn gamma_table(file_gamma: u32, display_gamma: u32, sixteen: bool) -> Vec<u16> {
+    let exponent = 1e10 / (file_gamma as f64 * display_gamma as f64);
+    let max = if sixteen { 65535.0 } else { 255.0 };
+    Vec::from_fn(max as uint + 1, |i| ((i as f64 / max).powf(exponent) * max + 0.5) as u16)
+}
+
+fn read_u32(data: &[u8], i: uint) -> u32 {
+    (data[i] as u32 << 24) | (data[i + 1] as u32 << 16) | (data[i + 2] as u32 << 8) | data[i + 3] as u32
+}
+
+// Decompress a complete ZLIB stream from an ancillary chunk, of at most max_size bytes.
+fn inflate_chunk(mut data: &[u8], max_size: uint) -> Result<Vec<u8>, PngError> {
+    let mut stream = InflateStream::from_zlib();
+    let mut out = Vec::new();
+    while data.len() > 0 {
+        let (used, output) = match stream.update(data) {
+            Ok(r) => r,
+            Err(e) => return Err(Inflate(e))
+        };
+        if out.len() + output.len() > max_size {
+            return Err(LimitExceeded(format!("decompressed data is over the limit of {} bytes", max_size)));
+        }
+        out.push_all(output);
+        data = data.slice_from(used);
+    }
+    if !stream.is_done() {
+        return Err(Malformed("incomplete compressed data".to_string()));
+    }
+    Ok(out)
+}
+
+// Extract the x-th sample of a scanline packed with `bits` bits per sample.
+fn unpack_sample(scanline: &[u8], x: uint, bits: uint) -> u8 {
+    if bits == 8 {
+        scanline[x]
+    } else {
+        let bit = x * bits;
+        (scanline[bit / 8] >> (8 - bits - bit % 8)) & ((1 << bits) - 1) as u8
+    }
+}
+
 enum State {
     CheckMagic(/*offset*/ u8),
     U16(U16Next),
@@ -759,6 +1646,8 @@ enum State {
     IhdrInterlaceMethod(/*width*/ u32, /*height*/ u32, /*bits*/ u8, /*color_type*/ u8, /*compression_method*/ u8, /*filter_method*/ u8),
     Plte(/*left*/ u32),
     Trns(/*left*/ u32, /*index*/ u32),
+    // The data of a chunk parsed once complete, see parse_color_chunk.
+    ChunkData(/*left*/ u32),
     IdatInflate(/*left*/ u32)
 }
 
@@ -773,24 +1662,505 @@ enum U32Next {
     U32ChunkSize,
     U32ChunkCRC(/*last_chunk*/ bool),
     U32IhdrWidth,
-    U32IhdrHeight(/*width*/ u32)
+    U32IhdrHeight(/*width*/ u32),
+    U32Gama
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum CrcMode {
+    // Every CRC mismatch is an error.
+    CrcStrict,
+    // CRC mismatches in ancillary chunks are only logged.
+    CrcWarnAncillary,
+    // CRCs aren't checked at all.
+    CrcIgnore
+}
+
+// Limits on what the decoder accepts, checked before allocating anything.
+#[deriving(Clone)]
+pub struct DecoderLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    // Size of the decoded pixel buffer.
+    pub max_pixel_bytes: uint,
+    // Total size of the decompressed IDAT data.
+    pub max_inflated_bytes: u64,
+    // Also limits the decompressed size of iCCP profiles.
+    pub max_ancillary_chunk_size: u32,
+    pub max_chunks: uint
+}
+
+impl DecoderLimits {
+    // Only ancillary chunks are limited by default (to 8MB, like libpng),
+    // otherwise anything allowed by the PNG specification is accepted.
+    pub fn new() -> DecoderLimits {
+        DecoderLimits {
+            max_width: (1 << 31) - 1,
+            max_height: (1 << 31) - 1,
+            max_pixel_bytes: ::std::uint::MAX,
+            max_inflated_bytes: ::std::u64::MAX,
+            max_ancillary_chunk_size: 8 * 1000 * 1000,
+            max_chunks: ::std::uint::MAX
+        }
+    }
+}
+
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum OutputMode {
+    // Expanded to KA8, KA16, RGBA8 or RGBA16, applying PLTE and tRNS.
+    OutputExpanded,
+    // Unfiltered samples in the image's own color type and bit depth,
+    // packed like in the PNG scanlines, with each row starting on a byte.
+    OutputNative,
+    // One byte (Pal8) per palette index, see Image::palette.
+    // Images without a palette are expanded instead.
+    OutputIndexed
+}
+
+// Byte order of 8-bit color pixels, written directly by the decoder.
+// Grayscale and 16-bit images aren't affected.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum PixelLayout {
+    LayoutRGBA8,
+    LayoutBGRA8,
+    // Alpha (including tRNS) is dropped.
+    LayoutRGB8,
+    LayoutARGB8
+}
+
+// Marks a channel left out of a layout.
+static NO_CHANNEL: u8 = 0xff;
+
+impl PixelLayout {
+    // Offsets of R, G, B and A in each pixel, and the pixel size, if not RGBA8.
+    fn offsets(self) -> Option<([u8, ..4], uint)> {
+        match self {
+            LayoutRGBA8 => None,
+            LayoutBGRA8 => Some(([2, 1, 0, 3], 4)),
+            LayoutRGB8 => Some(([0, 1, 2, NO_CHANNEL], 3)),
+            LayoutARGB8 => Some(([1, 2, 3, 0], 4))
+        }
+    }
+}
+
+// How 16-bit samples are reduced to 8 bits, see Transformations::strip_16.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum Strip16 {
+    // Keep the high byte.
+    StripTruncate,
+    // Scale to the nearest 8-bit value.
+    StripRound,
+    // Scale with a 4x4 ordered dither, alpha is rounded instead.
+    StripDither
+}
+
+// 4x4 Bayer matrix, used for ordered dithering.
+static BAYER_4X4: [u8, ..16] = [
+    0, 8, 2, 10,
+    12, 4, 14, 6,
+    3, 11, 1, 9,
+    15, 7, 13, 5
+];
+
+impl Strip16 {
+    // Reduce a sample of the pixel at (x, y).
+    fn apply(self, v: u16, x: uint, y: uint) -> u16 {
+        match self {
+            StripTruncate => v >> 8,
+            StripRound => ((v as u32 + 128) / 257) as u16,
+            StripDither => {
+                // A threshold between 0 and 1 (in 8-bit steps) for each pixel of a 4x4 tile.
+                let t = (BAYER_4X4[(y & 3) * 4 + (x & 3)] as u32 * 2 + 1) * 0xffff / 32;
+                ((v as u32 * 0xff + t) / 0xffff) as u16
+            }
+        }
+    }
+}
+
+// Conversions applied to each pixel as it's decoded, instead of the output
+// mode, layout and premultiplication of DecoderOptions. They're applied in the
+// order of the methods below, and without any, images are decoded as with
+// OutputNative.
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct Transformations {
+    expand: bool,
+    display_gamma: Option<u32>,
+    to_srgb: bool,
+    strip_16: Option<Strip16>,
+    rgb_to_gray: Option<(u16, u16, u16)>,
+    gray_to_rgb: bool,
+    strip_alpha: bool,
+    filler: Option<u8>
+}
+
+impl Transformations {
+    pub fn new() -> Transformations {
+        Transformations {
+            expand: false,
+            display_gamma: None,
+            to_srgb: false,
+            strip_16: None,
+            rgb_to_gray: None,
+            gray_to_rgb: false,
+            strip_alpha: false,
+            filler: None
+        }
+    }
+
+    // Palette images to RGBA8, sub-byte grayscale to 8 bits, and an alpha
+    // channel (from tRNS) for grayscale and RGB images.
+    pub fn expand(mut self) -> Transformations {
+        self.expand = true;
+        self
+    }
+
+    // Gamma correction from the gAMA of the file (if any) to a display gamma,
+    // times 100000 (e.g. 220000 for most displays). Alpha isn't changed.
+    pub fn gamma(mut self, display_gamma: u32) -> Transformations {
+        self.display_gamma = Some(display_gamma);
+        self
+    }
+
+    // Colors to 8-bit sRGB, from the iCCP profile if it's supported (see
+    // IccProfile::check), or else the primaries and white point of cHRM and
+    // the gamma of gAMA (sRGB if missing, or if there's an sRGB chunk), which
+    // also expands the image and makes it RGB. Replaces gamma.
+    pub fn to_srgb(mut self) -> Transformations {
+        self.to_srgb = true;
+        self
+    }
+
+    // 16-bit samples to 8 bits.
+    pub fn strip_16(mut self, mode: Strip16) -> Transformations {
+        self.strip_16 = Some(mode);
+        self
+    }
+
+    // RGB to gray levels, as the weighted sum of red, green and blue (with
+    // weights relative to their total, e.g. 6968, 23434, 2366 for BT.709,
+    // which are also used if all three are 0).
+    pub fn rgb_to_gray(mut self, red: u16, green: u16, blue: u16) -> Transformations {
+        self.rgb_to_gray = if red as uint + green as uint + blue as uint == 0 {
+            Some((6968, 23434, 2366))
+        } else {
+            Some((red, green, blue))
+        };
+        self
+    }
+
+    // Gray levels to RGB, repeated.
+    pub fn gray_to_rgb(mut self) -> Transformations {
+        self.gray_to_rgb = true;
+        self
+    }
+
+    pub fn strip_alpha(mut self) -> Transformations {
+        self.strip_alpha = true;
+        self
+    }
+
+    // An alpha sample with the given value (repeated for 16-bit samples),
+    // for images without alpha.
+    pub fn add_filler(mut self, alpha: u8) -> Transformations {
+        self.filler = Some(alpha);
+        self
+    }
+
+    // Whether to expand images of the given color type. Palette and sub-byte
+    // grayscale images are also expanded when anything else changes their pixels.
+    fn expands(&self, color_type: ColorType) -> bool {
+        self.expand || self.to_srgb || ((color_type.is_palette() || color_type.bit_depth() < 8) &&
+                        (self.display_gamma.is_some() || self.rgb_to_gray.is_some() ||
+                         self.gray_to_rgb || self.strip_alpha || self.filler.is_some()))
+    }
+
+    // Whether expanding pixels of the given color type without alpha adds it,
+    // from tRNS or the palette. Images only expanded because of the other
+    // transformations get alpha if there's a tRNS chunk.
+    fn trns_alpha(&self, color_type: ColorType, has_trns: bool) -> bool {
+        self.expands(color_type) && (self.expand || self.to_srgb || has_trns)
+    }
+
+    fn output_type(&self, color_type: ColorType, has_trns: bool) -> ColorType {
+        if !self.expands(color_type) && (color_type.is_palette() || color_type.bit_depth() < 8) {
+            return color_type;
+        }
+        let trns_alpha = self.trns_alpha(color_type, has_trns);
+        let (mut gray, mut alpha) = match color_type {
+            K1 | K2 | K4 | K8 | K16 => (true, trns_alpha),
+            KA8 | KA16 => (true, true),
+            RGB8 | RGB16 => (false, trns_alpha),
+            RGBA8 | RGBA16 => (false, true),
+            Pal1 | Pal2 | Pal4 | Pal8 => (false, trns_alpha)
+        };
+        let sixteen = color_type.bit_depth() == 16 && self.strip_16.is_none() && !self.to_srgb;
+        if self.to_srgb {
+            gray = false;
+        }
+        if self.rgb_to_gray.is_some() {
+            gray = true;
+        }
+        if self.gray_to_rgb {
+            gray = false;
+        }
+        if self.strip_alpha {
+            alpha = false;
+        }
+        if self.filler.is_some() {
+            alpha = true;
+        }
+        match (gray, alpha, sixteen) {
+            (true, false, false) => K8,
+            (true, true, false) => KA8,
+            (true, false, true) => K16,
+            (true, true, true) => KA16,
+            (false, false, false) => RGB8,
+            (false, true, false) => RGBA8,
+            (false, false, true) => RGB16,
+            (false, true, true) => RGBA16
+        }
+    }
+
+    // Everything but expand, on a pixel from read_pixel at (x, y).
+    fn apply(&self, sixteen: bool, gamma_table: Option<&[u16]>, srgb: Option<&SrgbTransform>,
+             mut color: [u16, ..3], mut alpha: Option<u16>, x: uint, y: uint) -> ([u16, ..3], Option<u16>) {
+        let mut sixteen = sixteen;
+        match (srgb, gamma_table) {
+            // Converting to sRGB replaces gamma correction.
+            (Some(srgb), _) => {
+                color = srgb.convert(color);
+                if sixteen {
+                    alpha = alpha.map(|a| StripRound.apply(a, x, y));
+                    sixteen = false;
+                }
+            }
+            (None, Some(table)) => for c in color.mut_iter() {
+                *c = table[*c as uint];
+            },
+            (None, None) => {}
+        }
+        match self.strip_16 {
+            Some(mode) if sixteen => {
+                for c in color.mut_iter() {
+                    *c = mode.apply(*c, x, y);
+                }
+                let alpha_mode = match mode {
+                    StripDither => StripRound,
+                    mode => mode
+                };
+                alpha = alpha.map(|a| alpha_mode.apply(a, x, y));
+                sixteen = false;
+            }
+            _ => {}
+        }
+        // Gray levels are already repeated, so only RGB to gray needs any work.
+        match self.rgb_to_gray {
+            Some((r, g, b)) => {
+                let total = r as u64 + g as u64 + b as u64;
+                let k = (color[0] as u64 * r as u64 + color[1] as u64 * g as u64 +
+                         color[2] as u64 * b as u64 + total / 2) / total;
+                color = [k as u16, ..3];
+            }
+            None => {}
+        }
+        if self.strip_alpha {
+            alpha = None;
+        }
+        if alpha.is_none() {
+            alpha = self.filler.map(|f| if sixteen { f as u16 * 257 } else { f as u16 });
+        }
+        (color, alpha)
+    }
+}
+
+#[deriving(Clone)]
+pub struct DecoderOptions {
+    pub crc_mode: CrcMode,
+    pub limits: DecoderLimits,
+    pub output: OutputMode,
+    // Only used with OutputExpanded.
+    pub layout: PixelLayout,
+    // Multiply color by alpha, for KA8 and 8-bit color images with alpha
+    // (only with OutputExpanded). See Image::unpremultiply.
+    pub premultiply: bool,
+    // Replaces the output mode, layout and premultiply, which have to be left
+    // as they are in DecoderOptions::new() (see Decoder::with_transformations).
+    pub transformations: Option<Transformations>
+}
+
+impl DecoderOptions {
+    pub fn new() -> DecoderOptions {
+        DecoderOptions {
+            crc_mode: CrcStrict,
+            limits: DecoderLimits::new(),
+            output: OutputExpanded,
+            layout: LayoutRGBA8,
+            premultiply: false,
+            transformations: None
+        }
+    }
 }
 
 pub struct Decoder {
     state: Option<State>,
-    image: Option<PartialImage>
+    image: Option<PartialImage>,
+    info: Option<ImageInfo>,
+    // Stop after IHDR, without allocating the image.
+    header_only: bool,
+    options: DecoderOptions,
+    chunk_name: [u8, ..4],
+    // Number of chunks started so far.
+    chunks: uint,
+    // Number of bytes decoded so far.
+    offset: u64,
+    // Number of bytes of the data given to next_state used before an error.
+    error_pos: uint,
+    // Number of bytes decompressed from IDAT so far.
+    inflated: u64,
+    // Data of the current chunk, for chunks only parsed once complete.
+    chunk_data: Vec<u8>,
+    crc: Crc32
 }
 
 impl Decoder {
     pub fn new() -> Decoder {
+        Decoder::with_options(DecoderOptions::new())
+    }
+
+    pub fn with_transformations(transformations: Transformations) -> Decoder {
+        Decoder::with_options(DecoderOptions {
+            transformations: Some(transformations),
+            ..DecoderOptions::new()
+        })
+    }
+
+    pub fn with_options(options: DecoderOptions) -> Decoder {
         Decoder {
             state: Some(CheckMagic(0)),
-            image: None
+            image: None,
+            info: None,
+            header_only: false,
+            options: options,
+            chunk_name: [0, ..4],
+            chunks: 0,
+            offset: 0,
+            error_pos: 0,
+            inflated: 0,
+            chunk_data: Vec::new(),
+            crc: Crc32::new()
+        }
+    }
+
+    // Whether a header-only decoder has seen all of IHDR, including its CRC.
+    fn header_done(&self) -> bool {
+        match self.state {
+            Some(U32(U32ChunkSize, 0, _)) => self.header_only && self.info.is_some(),
+            _ => false
+        }
+    }
+
+    fn with_context(&self, error: PngError) -> DecodeError {
+        let chunk = match self.state {
+            None | Some(CheckMagic(_)) | Some(U32(U32ChunkSize, _, _)) => None,
+            _ => Some((self.chunk_name, self.chunks - 1))
+        };
+        let scanline = match (self.state, &self.image) {
+            (Some(IdatInflate(_)), &Some(ref image)) => image.scanline_position(),
+            _ => None
+        };
+        DecodeError {
+            error: error,
+            offset: self.offset + self.error_pos as u64,
+            chunk: chunk,
+            scanline: scanline
+        }
+    }
+
+    fn check_crc(&self, crc: u32) -> Result<(), PngError> {
+        let computed = self.crc.checksum();
+        if crc == computed {
+            return Ok(());
+        }
+
+        // Ancillary chunks have a lowercase first letter.
+        let ancillary = (self.chunk_name[0] & 0x20) != 0;
+        let e = CrcMismatch(self.chunk_name, crc, computed);
+        match self.options.crc_mode {
+            CrcIgnore => Ok(()),
+            CrcWarnAncillary if ancillary => {
+                warn!("{}", e);
+                Ok(())
+            }
+            _ => Err(e)
         }
     }
 
-    fn next_state(&mut self, data: &[u8]) -> Result<uint, String> {
+    // Parse the complete data of a colorimetry chunk into the image's ColorInfo.
+    fn parse_color_chunk(&mut self) -> Result<(), PngError> {
+        let data = mem::replace(&mut self.chunk_data, Vec::new());
+        let data = data.as_slice();
+        let max_size = self.options.limits.max_ancillary_chunk_size as uint;
+        let color = &mut self.image.as_mut().unwrap().image.color_info;
+        match from_utf8(self.chunk_name).unwrap() {
+            "sRGB" => {
+                if data[0] > 3 {
+                    warn!("ignoring sRGB chunk with unknown rendering intent {}", data[0]);
+                } else {
+                    color.srgb_intent = Some(data[0]);
+                }
+            }
+            "cHRM" => {
+                let point = |i: uint| (read_u32(data, i * 8), read_u32(data, i * 8 + 4));
+                color.chromaticities = Some(Chromaticities {
+                    white: point(0),
+                    red: point(1),
+                    green: point(2),
+                    blue: point(3)
+                });
+            }
+            "iCCP" => {
+                // A Latin-1 name of 1 to 79 bytes and a NUL, then the
+                // compression method (0 for ZLIB) and the compressed profile.
+                // Like the other colorimetry chunks, a broken one is ignored.
+                let name_len = match data.iter().position(|&b| b == 0) {
+                    Some(n) if n >= 1 && n <= 79 => n,
+                    _ => {
+                        warn!("ignoring iCCP chunk with invalid profile name");
+                        return Ok(());
+                    }
+                };
+                if data.len() < name_len + 2 || data[name_len + 1] != 0 {
+                    warn!("ignoring iCCP chunk with unknown compression method");
+                    return Ok(());
+                }
+                let profile = match inflate_chunk(data.slice_from(name_len + 2), max_size) {
+                    Ok(profile) => profile,
+                    Err(LimitExceeded(m)) => return Err(LimitExceeded(m)),
+                    Err(e) => {
+                        warn!("ignoring iCCP chunk with invalid profile: {}", e);
+                        return Ok(());
+                    }
+                };
+                color.icc_profile = Some(IccProfile {
+                    name: data.slice_to(name_len).iter().map(|&b| b as char).collect(),
+                    data: profile
+                });
+            }
+            _ => {
+                color.cicp = Some(Cicp {
+                    primaries: data[0],
+                    transfer: data[1],
+                    matrix: data[2],
+                    full_range: data[3] != 0
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn next_state(&mut self, data: &[u8]) -> Result<uint, PngError> {
         let b = data[0];
+        self.error_pos = 0;
         macro_rules! ok2 (($n:expr, $state:expr) => ({
             self.state = Some($state);
             Ok($n as uint)
@@ -805,13 +2175,13 @@ impl Decoder {
 
         let state = match self.state {
             Some(state) => state,
-            None => return Err("called png::Decoder::next_state with non-existent state".to_string())
+            None => return Err(Malformed("data after IEND".to_string()))
         };
 
         match state {
             CheckMagic(i) => {
                 if b != MAGIC[i as uint] {
-                    Err(format!("PNG header mismatch, expected {:#02x} but found {:#02x} for byte {}", MAGIC[i as uint], b, i))
+                    Err(BadSignature(i))
                 } else if i < 7 {
                     ok!(CheckMagic(i + 1))
                 } else {
@@ -824,6 +2194,11 @@ impl Decoder {
                 match (next, value) {
                     (U16TrnsK, k) => {
                         let image = self.image.as_mut().unwrap();
+                        // Like libpng, only the bits used by the gray levels count.
+                        let k = match image.color_type.bit_depth() {
+                            16 => k,
+                            bits => k & ((1 << bits) - 1) as u16
+                        };
                         image.transparent_color = Some([k, k, k]);
                         ok!(skip_crc)
                     }
@@ -844,7 +2219,10 @@ impl Decoder {
                     match next {
                         U32ChunkSize => ok!(Chunk4CC(value)),
                         U32ChunkCRC(last_chunk) => {
-                            // TODO(eddyb) check the CRC.
+                            match self.check_crc(value) {
+                                Ok(()) => {}
+                                Err(m) => return Err(m)
+                            }
                             if last_chunk {
                                 self.state = None;
                                 Ok(1)
@@ -853,7 +2231,15 @@ impl Decoder {
                             }
                         }
                         U32IhdrWidth => ok_u32!(U32IhdrHeight(value)),
-                        U32IhdrHeight(w) => ok!(IhdrBits(w, value))
+                        U32IhdrHeight(w) => ok!(IhdrBits(w, value)),
+                        U32Gama => {
+                            if value == 0 {
+                                warn!("ignoring gAMA chunk with a gamma of 0");
+                            } else {
+                                self.info.as_mut().unwrap().gamma = Some(value);
+                            }
+                            ok!(skip_crc)
+                        }
                     }
                 }
             }
@@ -862,37 +2248,58 @@ impl Decoder {
             Chunk4CC2(size, [b0, b1]) => ok!(Chunk4CC3(size, [b0, b1, b])),
             Chunk4CC3(size, [b0, b1, b2]) => {
                 let name = [b0, b1, b2, b];
+                self.chunk_name = name;
                 let name = match from_utf8(name) {
                     Some(name) => name,
-                    None => return Err(format!("non-utf8 chunk name {:?}", name))
+                    None => return Err(Malformed(format!("non-utf8 chunk name {:?}", name)))
                 };
+                if self.info.is_none() && name != "IHDR" {
+                    return Err(ChunkOrder(format!("{} before IHDR", name)));
+                }
+                let limits = &self.options.limits;
+                if self.chunks > limits.max_chunks {
+                    return Err(LimitExceeded(format!("more than the limit of {} chunks", limits.max_chunks)));
+                }
+                // Ancillary chunks have a lowercase first letter.
+                if (b0 & 0x20) != 0 && size > limits.max_ancillary_chunk_size {
+                    return Err(LimitExceeded(format!("{} chunk of {} bytes is over the limit of {}",
+                                                     name, size, limits.max_ancillary_chunk_size)));
+                }
                 match name {
                     "IHDR" => {
-                        if self.image.is_some() {
-                            Err("duplicate IHDR".to_string())
+                        if self.info.is_some() {
+                            Err(ChunkOrder("duplicate IHDR".to_string()))
                         } else if size != size_of::<Ihdr>() as u32 {
-                            Err(format!("IHDR size mismatch, expected {} but found {}", size_of::<Ihdr>(), size))
+                            Err(Malformed(format!("IHDR size mismatch, expected {} but found {}",
+                                                  size_of::<Ihdr>(), size)))
                         } else {
                             ok_u32!(U32IhdrWidth)
                         }
                     }
                     "PLTE" => {
-                        if size > 0 && size % 3 != 0 {
-                            Err(format!("PLTE has non multiple of 3 size {}", size))
+                        if size % 3 != 0 {
+                            Err(Malformed(format!("PLTE has non multiple of 3 size {}", size)))
+                        } else if size == 0 || size > 256 * 3 {
+                            Err(Malformed(format!("PLTE has invalid number of entries {}", size / 3)))
                         } else {
                             match self.image {
-                                None => Err("PLTE before IHDR".to_string()),
+                                None => Err(ChunkOrder("PLTE before IHDR".to_string())),
                                 Some(ref mut image) => {
+                                    let info = self.info.as_mut().unwrap();
                                     if image.idat_inflate_stream.is_some() {
-                                        Err("PLTE after IDAT".to_string())
-                                    } else if image.palette.is_some() {
-                                        Err("duplicate PLTE".to_string())
-                                    } else if !image.color_type.is_palette() {
-                                        // Ignore a palette that's not used to decode the image.
-                                        ok!(IgnoreChunk(size))
+                                        Err(ChunkOrder("PLTE after IDAT".to_string()))
+                                    } else if info.has_plte {
+                                        Err(ChunkOrder("duplicate PLTE".to_string()))
                                     } else {
-                                        image.palette = Some(Vec::with_capacity(size as uint / 3 * 4));
-                                        ok!(Plte(size))
+                                        info.has_plte = true;
+                                        info.palette_size = size as uint / 3;
+                                        if !image.color_type.is_palette() {
+                                            // Ignore a palette that's not used to decode the image.
+                                            ok!(IgnoreChunk(size))
+                                        } else {
+                                            image.palette = Some(Vec::with_capacity(size as uint / 3 * 4));
+                                            ok!(Plte(size))
+                                        }
                                     }
                                 }
                             }
@@ -900,15 +2307,34 @@ impl Decoder {
                     }
                     "tRNS" => {
                         match self.image {
-                            None => Err("tRNS before IHDR".to_string()),
+                            None => Err(ChunkOrder("tRNS before IHDR".to_string())),
                             Some(ref mut image) => {
+                                let info = self.info.as_mut().unwrap();
                                 if image.idat_inflate_stream.is_some() {
-                                    Err("tRNS after IDAT".to_string())
+                                    Err(ChunkOrder("tRNS after IDAT".to_string()))
+                                } else if info.has_trns {
+                                    Err(ChunkOrder("duplicate tRNS".to_string()))
                                 } else {
+                                    info.has_trns = true;
                                     match image.color_type {
-                                        K1 | K2 | K4 | K8 | K16 => ok!(U16(U16TrnsK)),
-                                        Pal1 | Pal2 | Pal4 | Pal8 => ok!(Trns(size, 3)),
-                                        RGB8 | RGB16 => ok!(U16(U16TrnsR)),
+                                        K1 | K2 | K4 | K8 | K16 if size == 2 => ok!(U16(U16TrnsK)),
+                                        K1 | K2 | K4 | K8 | K16 => {
+                                            Err(Malformed(format!("tRNS size mismatch, expected 2 but found {}",
+                                                                  size)))
+                                        }
+                                        Pal1 | Pal2 | Pal4 | Pal8 => match image.palette {
+                                            None => Err(ChunkOrder("tRNS before PLTE".to_string())),
+                                            Some(ref palette) if size as uint > palette.len() / 4 => {
+                                                Err(Malformed(format!("tRNS has {} entries but PLTE only has {}",
+                                                                      size, palette.len() / 4)))
+                                            }
+                                            Some(_) => ok!(Trns(size, 3))
+                                        },
+                                        RGB8 | RGB16 if size == 6 => ok!(U16(U16TrnsR)),
+                                        RGB8 | RGB16 => {
+                                            Err(Malformed(format!("tRNS size mismatch, expected 6 but found {}",
+                                                                  size)))
+                                        }
                                         _ => ok!(IgnoreChunk(size))
                                     }
                                 }
@@ -917,21 +2343,81 @@ impl Decoder {
                     }
                     "IDAT" => {
                         if self.image.is_none() {
-                            Err("IDAT before IHDR".to_string())
+                            Err(ChunkOrder("IDAT before IHDR".to_string()))
                         } else if self.image.as_ref().unwrap().color_type.is_palette()
                             && self.image.as_ref().unwrap().palette.is_none() {
-                            Err("IDAT before PLTE".to_string())
+                            Err(ChunkOrder("IDAT before PLTE".to_string()))
                         } else {
-                            let stream = &mut self.image.as_mut().unwrap().idat_inflate_stream;
-                            if stream.is_none() {
-                                *stream = Some(box InflateStream::from_zlib());
+                            let info = self.info.as_ref().unwrap();
+                            let image = self.image.as_mut().unwrap();
+                            if image.idat_inflate_stream.is_none() {
+                                image.idat_inflate_stream = Some(box InflateStream::from_zlib());
+                                image.build_tables(info.gamma);
+                                let max_pixel_bytes = self.options.limits.max_pixel_bytes;
+                                match image.resolve_trns_alpha(info.has_trns, max_pixel_bytes) {
+                                    Ok(()) => {}
+                                    Err(e) => return Err(e)
+                                }
                             }
                             ok!(IdatInflate(size))
                         }
                     }
-                    "IEND" => ok_u32!(U32ChunkCRC(true)),
+                    "gAMA" => {
+                        let info = self.info.as_ref().unwrap();
+                        let after_idat = self.image.as_ref().map_or(false, |image| {
+                            image.idat_inflate_stream.is_some()
+                        });
+                        if size != 4 {
+                            warn!("ignoring gAMA chunk with invalid size {}", size);
+                            ok!(IgnoreChunk(size))
+                        } else if info.gamma.is_some() || info.has_plte || after_idat {
+                            // Like libpng, only the first gAMA before PLTE and IDAT is used.
+                            warn!("ignoring misplaced or duplicate gAMA chunk");
+                            ok!(IgnoreChunk(size))
+                        } else {
+                            ok_u32!(U32Gama)
+                        }
+                    }
+                    "sRGB" | "cHRM" | "iCCP" | "cICP" => {
+                        let expected_size = match name {
+                            "sRGB" => 1,
+                            "cHRM" => 32,
+                            "cICP" => 4,
+                            _ => 0
+                        };
+                        // Like gAMA, only the first one before PLTE and IDAT is used.
+                        let misplaced = match self.image {
+                            Some(ref image) => {
+                                let color = &image.image.color_info;
+                                let duplicate = match name {
+                                    "sRGB" => color.srgb_intent.is_some(),
+                                    "cHRM" => color.chromaticities.is_some(),
+                                    "cICP" => color.cicp.is_some(),
+                                    _ => color.icc_profile.is_some()
+                                };
+                                duplicate || self.info.as_ref().unwrap().has_plte ||
+                                    image.idat_inflate_stream.is_some()
+                            }
+                            None => true
+                        };
+                        if expected_size != 0 && size != expected_size {
+                            warn!("ignoring {} chunk with invalid size {}", name, size);
+                            ok!(IgnoreChunk(size))
+                        } else if misplaced {
+                            warn!("ignoring misplaced or duplicate {} chunk", name);
+                            ok!(IgnoreChunk(size))
+                        } else {
+                            self.chunk_data = Vec::with_capacity(size as uint);
+                            ok!(ChunkData(size))
+                        }
+                    }
+                    "IEND" => match self.image {
+                        Some(ref image) if image.is_complete() => ok_u32!(U32ChunkCRC(true)),
+                        // IDAT is missing, or ended before the last scanline or the Adler-32.
+                        _ => Err(Truncated)
+                    },
                     // TODO(eddyb) maybe save the data?
-                    "tEXt" | "iTXt" | "iCCP" | "pHYs" | "gAMA" | "cHRM" | "sBIT" | "sRGB" | "bKGD" => ok!(IgnoreChunk(size)),
+                    "tEXt" | "iTXt" | "pHYs" | "sBIT" | "bKGD" => ok!(IgnoreChunk(size)),
                     name => {
                         error!("skipping unrecognized PNG chunk `{}` (size={})", name, size);
                         ok!(IgnoreChunk(size))
@@ -960,7 +2446,21 @@ impl Decoder {
                     filter_method: f,
                     interlace_method: b
                 };
-                match header.to_image() {
+                let info = match header.to_info() {
+                    Ok(info) => info,
+                    Err(m) => return Err(m)
+                };
+                let limits = &self.options.limits;
+                if info.width > limits.max_width || info.height > limits.max_height {
+                    return Err(LimitExceeded(format!("{}x{} image is over the limit of {}x{}",
+                                                     info.width, info.height,
+                                                     limits.max_width, limits.max_height)));
+                }
+                self.info = Some(info);
+                if self.header_only {
+                    return ok!(skip_crc);
+                }
+                match header.to_image(&self.options) {
                     Ok(image) => {
                         self.image = Some(image);
                         ok!(skip_crc)
@@ -999,19 +2499,45 @@ impl Decoder {
                     ok2!(n, skip_crc)
                 }
             }
+            ChunkData(left) => {
+                let n = min(left, data.len() as u32);
+                self.chunk_data.push_all(data.slice_to(n as uint));
+                if left > n {
+                    ok2!(n, ChunkData(left - n))
+                } else {
+                    match self.parse_color_chunk() {
+                        Ok(()) => ok2!(n, skip_crc),
+                        Err(e) => {
+                            self.error_pos = n as uint - 1;
+                            Err(e)
+                        }
+                    }
+                }
+            }
             IdatInflate(left) => {
                 let mut n = min(left, data.len() as u32);
                 let image = self.image.as_mut().unwrap();
                 let mut stream = image.idat_inflate_stream.take_unwrap();
+                let total_in = stream.total_in();
                 match stream.update(data.slice_to(n as uint)) {
                     Ok((used, output)) => {
+                        // Errors in the output are found at the last byte it came from.
+                        self.error_pos = if used > 0 { used - 1 } else { 0 };
+                        self.inflated += output.len() as u64;
+                        if self.inflated > self.options.limits.max_inflated_bytes {
+                            return Err(LimitExceeded(format!("more than the limit of {} bytes of IDAT data",
+                                                             self.options.limits.max_inflated_bytes)));
+                        }
                         match image.update_idat(output) {
-                            Err(m) => return Err(format!("IDAT error: {:s}", m)),
+                            Err(e) => return Err(e),
                             _ => {}
                         }
                         n = used as u32;
                     }
-                    Err(m) => return Err(format!("IDAT decompression error: {:s}", m))
+                    Err(e) => {
+                        self.error_pos = (stream.total_in() - total_in) as uint;
+                        return Err(Inflate(e))
+                    }
                 }
                 // FIXME(eddyb) don't put back if it's no longer required.
                 image.idat_inflate_stream = Some(stream);
@@ -1025,12 +2551,36 @@ impl Decoder {
     }
 
     pub fn update<'a>(&'a mut self, mut data: &[u8]) -> ImageState<'a> {
-        while data.len() > 0 {
+        let check_crc = self.options.crc_mode != CrcIgnore;
+        while data.len() > 0 && !self.header_done() {
+            // The CRC covers the chunk type and data, but not the size or the CRC itself.
+            let in_crc = match self.state {
+                Some(CheckMagic(_)) |
+                Some(U32(U32ChunkSize, _, _)) |
+                Some(U32(U32ChunkCRC(_), _, _)) => false,
+                Some(Chunk4CC(_)) => {
+                    self.crc = Crc32::new();
+                    self.chunks += 1;
+                    true
+                }
+                _ => true
+            };
             match self.next_state(data) {
-                Ok(n) => { data = data.slice_from(n); }
-                Err(m) => return Error(m)
+                Ok(n) => {
+                    if check_crc && in_crc {
+                        self.crc.update(data.slice_to(n));
+                    }
+                    data = data.slice_from(n);
+                    self.offset += n as u64;
+                }
+                Err(e) => return Error(self.with_context(e))
             }
         }
+        // Keep the image's info up to date with the chunks seen so far.
+        match (self.image.as_mut(), self.info.as_ref()) {
+            (Some(partial), Some(info)) => partial.image.info = info.clone(),
+            _ => {}
+        }
         Partial(self.image.as_ref().map(|partial| &partial.image))
     }
 }
@@ -1048,43 +2598,80 @@ impl DecoderRef for Option<Box<Decoder>> {
                         *self = Some(decoder);
                         Partial(self.as_ref().unwrap().image.as_ref().map(|partial| &partial.image))
                     }
-                    Error(m) => Error(m),
+                    Error(e) => Error(e),
                     _ => Complete(decoder.image.take_unwrap().image)
                 }
             }
-            None => Error("called Option<~png::Decoder>::update on None".to_string())
+            None => Error(DecodeError::new(DecoderFinished))
         }
     }
 }
 
-/*
 pub fn is_png(image: &[u8]) -> bool {
-    do image.as_imm_buf |bytes, _len| {
-        unsafe {
-            ffi::png_sig_cmp(bytes, 0, 8) == 0
+    image.len() >= MAGIC.len() && image.slice_to(MAGIC.len()) == MAGIC.as_slice()
+}
+
+// Incrementally reads just the signature and IHDR, without allocating the image.
+pub struct Probe {
+    decoder: Decoder
+}
+
+impl Probe {
+    pub fn new() -> Probe {
+        let mut decoder = Decoder::new();
+        decoder.header_only = true;
+        Probe {
+            decoder: decoder
+        }
+    }
+
+    // Returns the header once all of IHDR has been read, or None if more data is needed.
+    pub fn update(&mut self, data: &[u8]) -> Result<Option<ImageInfo>, DecodeError> {
+        let error = match self.decoder.update(data) {
+            Error(e) => Some(e),
+            _ => None
+        };
+        match error {
+            Some(e) => Err(e),
+            None if self.decoder.header_done() => Ok(self.decoder.info.clone()),
+            None => Ok(None)
         }
     }
-}*/
+}
+
+pub fn probe(image: &[u8]) -> Result<ImageInfo, DecodeError> {
+    match Probe::new().update(image) {
+        Ok(Some(info)) => Ok(info),
+        Ok(None) => Err(DecodeError {
+            offset: image.len() as u64,
+            ..DecodeError::new(Truncated)
+        }),
+        Err(e) => Err(e)
+    }
+}
 
 #[allow(dead_code)]
 #[allow(deprecated_owned_vector)]
-pub fn load_png(path: &Path) -> Result<Image, String> {
+pub fn load_png(path: &Path) -> Result<Image, DecodeError> {
     match File::open_mode(path, io::Open, io::Read) {
         Ok(mut r) => match r.read_to_end() {
             Ok(data) => load_png_from_memory(data.as_slice()),
-            Err(m) => Err(m.to_str())
+            Err(e) => Err(DecodeError::new(Io(e)))
         },
-        Err(m) => Err(m.to_str())
+        Err(e) => Err(DecodeError::new(Io(e)))
     }
 }
 
 #[allow(dead_code)]
-pub fn load_png_from_memory(image: &[u8]) -> Result<Image, String> {
+pub fn load_png_from_memory(image: &[u8]) -> Result<Image, DecodeError> {
     let mut decoder = Some(box Decoder::new());
     match decoder.update(image) {
-        Partial(_) => Err("incomplete PNG file".to_string()),
+        Partial(_) => Err(DecodeError {
+            offset: image.len() as u64,
+            ..DecodeError::new(Truncated)
+        }),
         Complete(image) => Ok(image),
-        Error(m) => Err(m)
+        Error(e) => Err(e)
     }
 }
 
@@ -1093,12 +2680,23 @@ mod test {
     use extra::test::{bench, fmt_bench_samples};
     use std::io;
     use std::io::File;
+    use std::iter::range_step;
     use std::vec;
     use super::{load_png, load_png_from_memory, ColorType, RGBA8, KA8, Decoder, DecoderRef, Partial, Complete, Error};
+    use super::{K1, K2, K4, K8, K16, KA16, RGB8, RGB16, RGBA16, Pal4, Pal8, OutputNative, OutputIndexed, Palette};
+    use super::{encode_png_to_memory, Image, MAGIC};
+    use super::{DecodeError, DecoderOptions, DecoderLimits, CrcIgnore, CrcMismatch, Inflate, Truncated, LimitExceeded};
+    use super::{is_png, probe, Probe};
+    use super::{LayoutRGBA8, LayoutBGRA8, LayoutRGB8, LayoutARGB8, Pal1};
+    use super::premultiply_sample;
+    use super::{Transformations, StripTruncate, StripRound, StripDither};
+    use super::{ColorInfo, Chromaticities, Cicp, IccProfile, Malformed, Unsupported};
+    use super::{Adler32Mismatch, DeflateStream};
+    use super::checksum::Crc32;
 
     fn load_rgba8(file: &'static str, w: u32, h: u32) {
         match load_png(&Path::new(file)) {
-            Err(m) => fail!(m),
+            Err(e) => fail!("{}", e),
             Ok(image) => {
                 assert_eq!(image.color_type, RGBA8);
                 assert_eq!(image.width, w);
@@ -1113,6 +2711,1076 @@ mod test {
         load_rgba8("test_store.png", 10, 10);
     }
 
+    #[test]
+    fn test_stored_idat() {
+        // Level 0 only has stored blocks, which IDAT data can also be made of.
+        let image = Image::new(67, 45, RGBA8, noise(67 * 45 * 4));
+        let png = encode_png_to_memory(&image).unwrap();
+        let mut stream = DeflateStream::to_zlib(0);
+        let mut raw = Vec::new();
+        for row in image.pixels.as_slice().chunks(67 * 4) {
+            raw.push(0u8);
+            raw.push_all(row);
+        }
+        let mut idat = Vec::from_slice(stream.update(raw.as_slice()));
+        idat.push_all(stream.finish());
+        let mut stored = Vec::from_slice(png.slice_to(8 + 25));
+        stored.push_all(chunk("IDAT", idat.as_slice()).as_slice());
+        stored.push_all(chunk("IEND", []).as_slice());
+        assert_eq!(load_png_from_memory(stored.as_slice()).unwrap().pixels, image.pixels);
+    }
+
+    #[test]
+    fn test_encode_chunks() {
+        let image = Image::new(1, 1, RGBA8, vec![0xff, 0x00, 0x00, 0xff]);
+        let png = encode_png_to_memory(&image).unwrap();
+        assert_eq!(png.slice_to(8), MAGIC.as_slice());
+        assert_eq!(png.slice_from(png.len() - 12),
+                   [0, 0, 0, 0, 'I' as u8, 'E' as u8, 'N' as u8, 'D' as u8, 0xae, 0x42, 0x60, 0x82].as_slice());
+
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert_eq!(decoded.color_type, RGBA8);
+        assert_eq!((decoded.width, decoded.height), (1, 1));
+        assert_eq!(decoded.pixels, image.pixels);
+
+        match encode_png_to_memory(&Image::new(2, 2, RGBA8, vec![0xff, 0x00, 0x00, 0xff])) {
+            Err(Malformed(_)) => {}
+            r => fail!("unexpected result {}", r)
+        }
+    }
+
+    // Pseudo-random (and so mostly incompressible) pixels.
+    fn noise(len: uint) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        Vec::from_fn(len, |_| {
+            x = x * 1103515245 + 12345;
+            (x >> 16) as u8
+        })
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        for &(color_type, bytes) in [(KA8, 2u), (RGBA8, 4u)].iter() {
+            let image = Image::new(67, 45, color_type, noise(67 * 45 * bytes));
+            let png = encode_png_to_memory(&image).unwrap();
+            match load_png_from_memory(png.as_slice()) {
+                Err(e) => fail!("{}", e),
+                Ok(decoded) => {
+                    assert_eq!(decoded.color_type, color_type);
+                    assert_eq!(decoded.width, image.width);
+                    assert_eq!(decoded.height, image.height);
+                    assert_eq!(decoded.pixels, image.pixels);
+                    assert_eq!(decoded.info, image.info);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_palette_to_rgba() {
+        let rgb = vec![0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff];
+        let indices = vec![0u8, 1, 2, 3, 2, 1];
+        for alpha in [vec![0xffu8, 0xff, 0xff, 0xff], vec![0x00u8, 0x80, 0xff, 0xff]].iter() {
+            let mut image = Image::new(3, 2, Pal8, indices.clone());
+            image.palette = Some(Palette { rgb: rgb.clone(), alpha: alpha.clone() });
+            let png = encode_png_to_memory(&image).unwrap();
+            let decoded = load_png_from_memory(png.as_slice()).unwrap();
+            assert_eq!(decoded.color_type, RGBA8);
+            assert!(decoded.info.has_plte);
+            assert_eq!(decoded.info.palette_size, 4);
+            // Opaque entries at the end of the palette are left out of tRNS.
+            assert_eq!(decoded.info.has_trns, alpha.as_slice()[0] != 0xff);
+            for (pixel, &index) in decoded.pixels.as_slice().chunks(4).zip(indices.iter()) {
+                let i = index as uint;
+                assert_eq!(pixel.slice_to(3), rgb.slice(i * 3, i * 3 + 3));
+                assert_eq!(pixel[3], alpha.as_slice()[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_16_bit_expansion() {
+        // Samples with alpha are kept as they are.
+        for &(color_type, expanded, bytes) in [(KA16, KA16, 4u), (RGBA16, RGBA16, 8u)].iter() {
+            let image = Image::new(13, 7, color_type, noise(13 * 7 * bytes));
+            let png = encode_png_to_memory(&image).unwrap();
+            let decoded = load_png_from_memory(png.as_slice()).unwrap();
+            assert_eq!(decoded.color_type, expanded);
+            assert_eq!(decoded.pixels, image.pixels);
+        }
+
+        // The second gray level only differs from the tRNS one in its low byte.
+        let image = Image::new(3, 1, K16, vec![0x12, 0x34, 0x12, 0x35, 0xff, 0xff]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert_eq!(decoded.color_type, KA16);
+        assert!(!decoded.info.has_plte && !decoded.info.has_trns);
+        assert_eq!(decoded.pixels, vec![0x12, 0x34, 0xff, 0xff, 0x12, 0x35, 0xff, 0xff,
+                                        0xff, 0xff, 0xff, 0xff]);
+        let png = with_chunk(png.as_slice(), "tRNS", [0x12, 0x34]);
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert!(decoded.info.has_trns);
+        assert_eq!(decoded.transparent_color, Some([0x1234, 0x1234, 0x1234]));
+        assert_eq!(decoded.pixels, vec![0x12, 0x34, 0x00, 0x00, 0x12, 0x35, 0xff, 0xff,
+                                        0xff, 0xff, 0xff, 0xff]);
+        // A tRNS chunk of the wrong size can't be read as the gray level.
+        let png = encode_png_to_memory(&image).unwrap();
+        let png = with_chunk(png.as_slice(), "tRNS", [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        match load_png_from_memory(png.as_slice()) {
+            Err(e) => match e.error {
+                Malformed(_) => {}
+                _ => fail!("unexpected error {}", e)
+            },
+            Ok(_) => fail!("tRNS of the wrong size wasn't detected")
+        }
+
+        // Same for the blue sample of the second color.
+        let image = Image::new(2, 1, RGB16, vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc,
+                                                 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let png = with_chunk(png.as_slice(), "tRNS", [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        // A suggested palette, which is ignored (but still counted).
+        let png = with_chunk(png.as_slice(), "PLTE", [0x00, 0x00, 0x00, 0xff, 0xff, 0xff]);
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert!(decoded.info.has_plte && decoded.info.has_trns);
+        assert_eq!(decoded.info.palette_size, 2);
+        assert!(decoded.palette.is_none());
+        assert_eq!(decoded.color_type, RGBA16);
+        assert_eq!(decoded.pixels, vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0x00, 0x00,
+                                        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd, 0xff, 0xff]);
+    }
+
+    fn decode_with_options(png: &[u8], options: DecoderOptions) -> Image {
+        let mut decoder = Some(box Decoder::with_options(options));
+        match decoder.update(png) {
+            Complete(image) => image,
+            Partial(_) => fail!("incomplete PNG file"),
+            Error(e) => fail!("{}", e)
+        }
+    }
+
+    #[test]
+    fn test_native_output() {
+        let native = DecoderOptions {
+            output: OutputNative,
+            ..DecoderOptions::new()
+        };
+        for &color_type in [K1, K2, K4, K8, K16, KA16, RGB8, RGB16].iter() {
+            let row_bytes = (13 * color_type.pixel_bits() + 7) / 8;
+            let mut image = Image::new(13, 7, color_type, noise(row_bytes * 7));
+            // Along with the transparent color of the types without alpha.
+            image.transparent_color = match color_type {
+                KA16 => None,
+                c if c.is_gray() => Some([1, 1, 1]),
+                _ => Some([1, 2, 3])
+            };
+            let png = encode_png_to_memory(&image).unwrap();
+            let decoded = decode_with_options(png.as_slice(), native.clone());
+            assert_eq!(decoded.color_type, color_type);
+            assert_eq!(decoded.pixels, image.pixels);
+            assert_eq!(decoded.transparent_color, image.transparent_color);
+            assert!(encode_png_to_memory(&decoded).unwrap() == png);
+        }
+
+        // Expanded, 1-bit samples become 0x00 or 0xff.
+        let image = Image::new(8, 1, K1, vec![0b1010_0110]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let decoded = decode_with_options(png.as_slice(), DecoderOptions::new());
+        assert_eq!(decoded.color_type, KA8);
+        assert_eq!(decoded.pixels, vec![0xff, 0xff, 0x00, 0xff, 0xff, 0xff, 0x00, 0xff,
+                                        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0xff]);
+    }
+
+    // The PNG file of an image without a palette, Adam7 interlaced.
+    fn encode_interlaced(image: &Image) -> Vec<u8> {
+        let png = encode_png_to_memory(image).unwrap();
+        let mut ihdr = Vec::from_slice(png.slice(8 + 8, 8 + 8 + 13));
+        *ihdr.get_mut(12) = 1;
+
+        let bits = image.color_type.pixel_bits();
+        let (w, h) = (image.width as uint, image.height as uint);
+        let row_bytes = (w * bits + 7) / 8;
+        let passes = [(0u, 0u, 8u, 8u), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+                      (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+        let mut raw = Vec::new();
+        for &(x0, y0, dx, dy) in passes.iter() {
+            if x0 >= w || y0 >= h {
+                continue;
+            }
+            for y in range_step(y0, h, dy) {
+                let row = image.pixels.slice(y * row_bytes, (y + 1) * row_bytes);
+                let mut line = Vec::from_elem(((w - x0 + dx - 1) / dx * bits + 7) / 8, 0u8);
+                for (i, x) in range_step(x0, w, dx).enumerate() {
+                    for b in range(0, bits) {
+                        let (from, to) = (x * bits + b, i * bits + b);
+                        if row[from / 8] & (0x80u8 >> (from % 8)) != 0 {
+                            *line.get_mut(to / 8) |= 0x80u8 >> (to % 8);
+                        }
+                    }
+                }
+                raw.push(0u8);
+                raw.push_all(line.as_slice());
+            }
+        }
+        let mut stream = DeflateStream::to_zlib(6);
+        let mut idat = Vec::from_slice(stream.update(raw.as_slice()));
+        idat.push_all(stream.finish());
+
+        let mut out = Vec::from_slice(MAGIC);
+        out.push_all(chunk("IHDR", ihdr.as_slice()).as_slice());
+        out.push_all(chunk("IDAT", idat.as_slice()).as_slice());
+        out.push_all(chunk("IEND", []).as_slice());
+        out
+    }
+
+    #[test]
+    fn test_sub_byte_round_trip() {
+        let native = DecoderOptions {
+            output: OutputNative,
+            ..DecoderOptions::new()
+        };
+        for &color_type in [K1, K2, K4].iter() {
+            let bits = color_type.pixel_bits();
+            let max = (1u << bits) - 1;
+            // Widths that leave part of the last byte of some (or all) passes unused.
+            for &w in [1u, 3, 5, 7, 13].iter() {
+                let row_bytes = (w * bits + 7) / 8;
+                let mut pixels = noise(row_bytes * 9);
+                // The padding bits are zero, as the decoder leaves them.
+                let used = w * bits % 8;
+                if used != 0 {
+                    for row in pixels.as_mut_slice().mut_chunks(row_bytes) {
+                        row[row_bytes - 1] &= !(0xffu8 >> used);
+                    }
+                }
+                let image = Image::new(w as u32, 9, color_type, pixels);
+                let mut expected = Vec::with_capacity(w * 9 * 2);
+                for row in image.pixels.as_slice().chunks(row_bytes) {
+                    for x in range(0, w) {
+                        let shift = 8 - bits - x * bits % 8;
+                        let v = (row[x * bits / 8] >> shift) as uint & max;
+                        expected.push_all([(v * 0xff / max) as u8, 0xff]);
+                    }
+                }
+
+                let png = encode_png_to_memory(&image).unwrap();
+                let interlaced = encode_interlaced(&image);
+                for png in [png, interlaced].iter() {
+                    let decoded = load_png_from_memory(png.as_slice()).unwrap();
+                    assert_eq!(decoded.color_type, KA8);
+                    assert_eq!(decoded.pixels, expected);
+                    let decoded = decode_with_options(png.as_slice(), native.clone());
+                    assert_eq!(decoded.color_type, color_type);
+                    assert_eq!(decoded.pixels, image.pixels);
+                }
+            }
+        }
+
+        // Only the low bits of the tRNS gray level are compared.
+        let image = Image::new(2, 1, K2, vec![0x60]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let png = with_chunk(png.as_slice(), "tRNS", [0xff, 0xfd]);
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert_eq!(decoded.transparent_color, Some([1, 1, 1]));
+        assert_eq!(decoded.pixels, vec![0x55, 0x00, 0xaa, 0xff]);
+    }
+
+    #[test]
+    fn test_indexed_output() {
+        let palette = Palette {
+            rgb: vec![0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0x00],
+            alpha: vec![0x00, 0x80, 0xff]
+        };
+        // 5 pixels per row, packed 2 per byte.
+        let indices = [0u8, 1, 2, 1, 0, 2, 2, 1, 0, 0];
+        let mut image = Image::new(5, 2, Pal4, vec![0x01, 0x21, 0x00, 0x22, 0x10, 0x00]);
+        image.palette = Some(palette.clone());
+        let png = encode_png_to_memory(&image).unwrap();
+
+        let decoded = decode_with_options(png.as_slice(), DecoderOptions {
+            output: OutputIndexed,
+            ..DecoderOptions::new()
+        });
+        assert_eq!(decoded.color_type, Pal8);
+        assert_eq!(decoded.pixels.as_slice(), indices.as_slice());
+        assert_eq!(decoded.palette, Some(palette.clone()));
+        assert!(decoded.info.has_plte && decoded.info.has_trns);
+        assert_eq!(decoded.info.palette_size, 3);
+
+        let decoded = decode_with_options(png.as_slice(), DecoderOptions::new());
+        assert_eq!(decoded.color_type, RGBA8);
+        for (pixel, &index) in decoded.pixels.as_slice().chunks(4).zip(indices.iter()) {
+            let rgba = palette.rgba(index as uint);
+            assert_eq!(pixel, rgba.as_slice());
+        }
+
+        // Palettes that can't be written as PLTE and tRNS.
+        let bad_palettes = [
+            Palette { rgb: vec![0x00, 0x00, 0x00, 0xff], alpha: vec![0xff] },
+            Palette { rgb: Vec::new(), alpha: Vec::new() },
+            Palette { rgb: Vec::from_elem(17 * 3, 0u8), alpha: Vec::from_elem(17, 0xffu8) }
+        ];
+        for bad in bad_palettes.iter() {
+            image.palette = Some(bad.clone());
+            match encode_png_to_memory(&image) {
+                Err(Malformed(_)) => {}
+                r => fail!("unexpected result {}", r)
+            }
+        }
+    }
+
+    // The PNG file of an 8-bit image without a palette, with each scanline
+    // using the next filter type.
+    fn encode_filtered(image: &Image) -> Vec<u8> {
+        fn paeth(a: u8, b: u8, c: u8) -> u8 {
+            let p = a as int + b as int - c as int;
+            let (pa, pb, pc) = ((p - a as int).abs(), (p - b as int).abs(), (p - c as int).abs());
+            if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+        }
+        let png = encode_png_to_memory(image).unwrap();
+        let bpp = image.color_type.pixel_bits() / 8;
+        let row_bytes = image.width as uint * bpp;
+        let zeros = Vec::from_elem(row_bytes, 0u8);
+        let mut prev = zeros.as_slice();
+        let mut raw = Vec::new();
+        for (y, row) in image.pixels.as_slice().chunks(row_bytes).enumerate() {
+            let filter = (y % 5) as u8;
+            raw.push(filter);
+            for i in range(0, row_bytes) {
+                let (a, b) = (if i >= bpp { row[i - bpp] } else { 0 }, prev[i]);
+                let c = if i >= bpp { prev[i - bpp] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c)
+                };
+                raw.push(row[i] - predicted);
+            }
+            prev = row;
+        }
+        let mut stream = DeflateStream::to_zlib(6);
+        let mut idat = Vec::from_slice(stream.update(raw.as_slice()));
+        idat.push_all(stream.finish());
+
+        let mut out = Vec::from_slice(png.slice_to(8 + 25));
+        out.push_all(chunk("IDAT", idat.as_slice()).as_slice());
+        out.push_all(chunk("IEND", []).as_slice());
+        out
+    }
+
+    #[test]
+    fn test_pixel_layouts() {
+        for &(source, bytes) in [(RGB8, 3u), (RGBA8, 4u)].iter() {
+            let image = Image::new(67, 45, source, noise(67 * 45 * bytes));
+            let png = encode_filtered(&image);
+            let rgba = load_png_from_memory(png.as_slice()).unwrap();
+            assert_eq!(rgba.layout, LayoutRGBA8);
+            assert_eq!(rgba.pixels, load_png_from_memory(encode_png_to_memory(&image).unwrap().as_slice())
+                                        .unwrap().pixels);
+            for &layout in [LayoutBGRA8, LayoutRGB8, LayoutARGB8].iter() {
+                // Which RGBA channel ends up in each byte of the pixel.
+                let (color_type, order) = match layout {
+                    LayoutBGRA8 => (RGBA8, vec![2u, 1, 0, 3]),
+                    LayoutRGB8 => (RGB8, vec![0u, 1, 2]),
+                    _ => (RGBA8, vec![3u, 0, 1, 2])
+                };
+                let mut expected = Vec::with_capacity(rgba.pixels.len() / 4 * order.len());
+                for pixel in rgba.pixels.as_slice().chunks(4) {
+                    for &c in order.iter() {
+                        expected.push(pixel[c]);
+                    }
+                }
+
+                // Feed a few bytes at a time, to split pixels between updates.
+                let mut decoder = Some(box Decoder::with_options(DecoderOptions {
+                    layout: layout,
+                    ..DecoderOptions::new()
+                }));
+                let mut decoded = None;
+                for chunk in png.as_slice().chunks(7) {
+                    match decoder.update(chunk) {
+                        Partial(_) => {}
+                        Complete(image) => {
+                            decoded = Some(image);
+                            break;
+                        }
+                        Error(e) => fail!("{}", e)
+                    }
+                }
+                let decoded = decoded.expect("incomplete PNG file");
+                assert_eq!(decoded.color_type, color_type);
+                assert_eq!(decoded.layout, layout);
+                assert!(decoded.pixels == expected);
+
+                // The other layouts are encoded as RGBA.
+                if color_type != RGB8 {
+                    let png = encode_png_to_memory(&decoded).unwrap();
+                    assert!(load_png_from_memory(png.as_slice()).unwrap().pixels == rgba.pixels);
+                }
+            }
+        }
+
+        // Palette entries are stored in the layout too.
+        let mut image = Image::new(2, 1, Pal1, vec![0x40]);
+        image.palette = Some(Palette {
+            rgb: vec![0x10, 0x20, 0x30, 0x40, 0x50, 0x60],
+            alpha: vec![0x70, 0x80]
+        });
+        let png = encode_png_to_memory(&image).unwrap();
+        let decoded = decode_with_options(png.as_slice(), DecoderOptions {
+            layout: LayoutARGB8,
+            ..DecoderOptions::new()
+        });
+        assert_eq!(decoded.pixels, vec![0x70, 0x10, 0x20, 0x30, 0x80, 0x40, 0x50, 0x60]);
+    }
+
+    #[test]
+    fn test_premultiplied_output() {
+        let options = DecoderOptions {
+            premultiply: true,
+            ..DecoderOptions::new()
+        };
+        for &(color_type, bytes) in [(KA8, 2u), (RGBA8, 4u)].iter() {
+            let image = Image::new(67, 45, color_type, noise(67 * 45 * bytes));
+            let png = encode_png_to_memory(&image).unwrap();
+            let mut decoded = decode_with_options(png.as_slice(), options.clone());
+            assert!(decoded.premultiplied);
+            let pixels = decoded.pixels.as_slice().chunks(bytes).zip(image.pixels.as_slice().chunks(bytes));
+            for (p, q) in pixels {
+                let a = q[bytes - 1];
+                for c in range(0, bytes - 1) {
+                    // Rounded to the nearest.
+                    assert_eq!(p[c] as uint, (q[c] as uint * a as uint * 2 + 255) / 510);
+                }
+                assert_eq!(p[bytes - 1], a);
+            }
+            assert!(encode_png_to_memory(&decoded).is_err());
+
+            // Unpremultiplying is exact for opaque pixels, and close enough for
+            // the others to premultiply back to the same values.
+            let premultiplied = decoded.pixels.clone();
+            decoded.unpremultiply();
+            assert!(!decoded.premultiplied);
+            let pixels = decoded.pixels.as_slice().chunks(bytes).zip(premultiplied.as_slice().chunks(bytes));
+            for ((p, q), r) in pixels.zip(image.pixels.as_slice().chunks(bytes)) {
+                let a = q[bytes - 1];
+                for c in range(0, bytes - 1) {
+                    assert_eq!(premultiply_sample(p[c], a), q[c]);
+                    if a == 0xff {
+                        assert_eq!(p[c], r[c]);
+                    }
+                }
+            }
+        }
+
+        // 16-bit samples aren't premultiplied.
+        let image = Image::new(13, 7, RGBA16, noise(13 * 7 * 8));
+        let png = encode_png_to_memory(&image).unwrap();
+        let decoded = decode_with_options(png.as_slice(), options.clone());
+        assert!(!decoded.premultiplied);
+        assert_eq!(decoded.pixels, image.pixels);
+
+        // Premultiplied BGRA, from a file with filtered scanlines.
+        let image = Image::new(67, 45, RGBA8, noise(67 * 45 * 4));
+        let png = encode_filtered(&image);
+        let rgba = load_png_from_memory(png.as_slice()).unwrap();
+        assert_eq!(rgba.pixels, image.pixels);
+        let bgra = decode_with_options(png.as_slice(), DecoderOptions {
+            layout: LayoutBGRA8,
+            ..options
+        });
+        assert!(bgra.premultiplied);
+        for (p, q) in bgra.pixels.as_slice().chunks(4).zip(rgba.pixels.as_slice().chunks(4)) {
+            assert_eq!(p[0], premultiply_sample(q[2], q[3]));
+            assert_eq!(p[1], premultiply_sample(q[1], q[3]));
+            assert_eq!(p[2], premultiply_sample(q[0], q[3]));
+            assert_eq!(p[3], q[3]);
+        }
+    }
+
+    fn decode_with_transformations(png: &[u8], transformations: Transformations) -> Image {
+        let mut decoder = Some(box Decoder::with_transformations(transformations));
+        match decoder.update(png) {
+            Complete(image) => image,
+            Partial(_) => fail!("incomplete PNG file"),
+            Error(e) => fail!("{}", e)
+        }
+    }
+
+    // Whether all the samples are within 1 of each other, for color conversions.
+    fn close(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(&x, &y)| {
+            x as int - y as int <= 1 && y as int - x as int <= 1
+        })
+    }
+
+    #[test]
+    fn test_transformations() {
+        let image = Image::new(13, 7, RGB16, noise(13 * 7 * 6));
+        let png = encode_png_to_memory(&image).unwrap();
+        let strip_16 = |mode| decode_with_transformations(png.as_slice(), Transformations::new().strip_16(mode));
+        let truncated = strip_16(StripTruncate);
+        let rounded = strip_16(StripRound);
+        let dithered = strip_16(StripDither);
+        assert_eq!(truncated.color_type, RGB8);
+        for (j, &v) in image.pixels_u16().unwrap().iter().enumerate() {
+            assert_eq!(*truncated.pixels.get(j), (v >> 8) as u8);
+            let error = *rounded.pixels.get(j) as int * 257 - v as int;
+            assert!(error >= -128 && error <= 128);
+            let d = *dithered.pixels.get(j) as uint;
+            assert!(d == v as uint / 257 || d == v as uint / 257 + 1);
+        }
+        assert!(dithered.pixels != rounded.pixels);
+
+        // RGB to gray without alpha, then back to RGB with filler alpha.
+        let image = Image::new(13, 7, RGBA8, noise(13 * 7 * 4));
+        let png = encode_png_to_memory(&image).unwrap();
+        let gray = decode_with_transformations(png.as_slice(),
+                                               Transformations::new().rgb_to_gray(1, 1, 1).strip_alpha());
+        assert_eq!(gray.color_type, K8);
+        for (&k, p) in gray.pixels.iter().zip(image.pixels.as_slice().chunks(4)) {
+            assert_eq!(k as uint, (p[0] as uint + p[1] as uint + p[2] as uint + 1) / 3);
+        }
+        // Weights that are all 0 are replaced by the BT.709 ones.
+        let bt709 = |red, green, blue| {
+            decode_with_transformations(png.as_slice(), Transformations::new().rgb_to_gray(red, green, blue))
+        };
+        assert_eq!(bt709(0, 0, 0).pixels, bt709(6968, 23434, 2366).pixels);
+        let rgbx = decode_with_transformations(png.as_slice(), Transformations::new().rgb_to_gray(1, 1, 1)
+                                                                                     .gray_to_rgb()
+                                                                                     .strip_alpha()
+                                                                                     .add_filler(0x80));
+        assert_eq!(rgbx.color_type, RGBA8);
+        for (p, &k) in rgbx.pixels.as_slice().chunks(4).zip(gray.pixels.iter()) {
+            assert_eq!((p[0], p[1], p[2], p[3]), (k, k, k, 0x80));
+        }
+
+        // Expanding sub-byte gray levels, as done by default.
+        let image = Image::new(5, 3, K2, noise(2 * 3));
+        let png = encode_png_to_memory(&image).unwrap();
+        let expanded = decode_with_transformations(png.as_slice(), Transformations::new().expand());
+        assert_eq!(expanded.color_type, KA8);
+        assert_eq!(expanded.pixels, load_png_from_memory(png.as_slice()).unwrap().pixels);
+        // Which gray_to_rgb needs, even without expand, but without adding alpha.
+        let rgb = decode_with_transformations(png.as_slice(), Transformations::new().gray_to_rgb());
+        assert_eq!(rgb.color_type, RGB8);
+        for (p, ka) in rgb.pixels.as_slice().chunks(3).zip(expanded.pixels.as_slice().chunks(2)) {
+            assert_eq!((p[0], p[1], p[2]), (ka[0], ka[0], ka[0]));
+        }
+        // Unless there's a tRNS chunk.
+        let png = with_chunk(png.as_slice(), "tRNS", [0, *image.pixels.get(0) >> 6]);
+        let expanded = load_png_from_memory(png.as_slice()).unwrap();
+        let rgba = decode_with_transformations(png.as_slice(), Transformations::new().gray_to_rgb());
+        assert_eq!(rgba.color_type, RGBA8);
+        for (p, ka) in rgba.pixels.as_slice().chunks(4).zip(expanded.pixels.as_slice().chunks(2)) {
+            assert_eq!((p[0], p[1], p[2], p[3]), (ka[0], ka[0], ka[0], ka[1]));
+        }
+        assert_eq!(*rgba.pixels.get(3), 0);
+        // Other options that the transformations replace aren't silently ignored.
+        let mut decoder = Some(box Decoder::with_options(DecoderOptions {
+            layout: LayoutBGRA8,
+            transformations: Some(Transformations::new().strip_16(StripRound)),
+            ..DecoderOptions::new()
+        }));
+        match decoder.update(png.as_slice()) {
+            Error(e) => match e.error {
+                Unsupported(_) => {}
+                _ => fail!("unexpected error {}", e)
+            },
+            _ => fail!("layout and transformations weren't rejected")
+        }
+        // Without anything to do, the samples are left as they are.
+        let native = decode_with_transformations(png.as_slice(), Transformations::new());
+        assert_eq!(native.color_type, K2);
+        assert_eq!(native.pixels.len(), image.pixels.len());
+    }
+
+    fn chunk(name: &str, data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u32;
+        let mut out = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        let mut crc = Crc32::new();
+        crc.update(name.as_bytes());
+        crc.update(data);
+        out.push_all(name.as_bytes());
+        out.push_all(data);
+        let crc = crc.checksum();
+        out.push_all([(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+        out
+    }
+
+    // The PNG file with an extra chunk right after IHDR.
+    fn with_chunk(png: &[u8], name: &str, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::from_slice(png.slice_to(8 + 25));
+        out.push_all(chunk(name, data).as_slice());
+        out.push_all(png.slice_from(8 + 25));
+        out
+    }
+
+    fn be_u32s(values: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &v in values.iter() {
+            out.push_all([(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_gamma() {
+        let image = Image::new(16, 16, K8, Vec::from_fn(256, |i| i as u8));
+        let png = encode_png_to_memory(&image).unwrap();
+        // 1/1.8, as used on old Macs.
+        let png = with_chunk(png.as_slice(), "gAMA", [0, 0, 0xd9, 0x03]);
+        let decoded = load_png_from_memory(png.as_slice()).unwrap();
+        assert_eq!(decoded.info.gamma, Some(55555));
+        assert_eq!(decoded.pixels.len(), 256 * 2);
+        // A gAMA chunk of the wrong size is ignored.
+        let bad = with_chunk(encode_png_to_memory(&image).unwrap().as_slice(), "gAMA", [0, 0xd9, 0x03]);
+        assert_eq!(load_png_from_memory(bad.as_slice()).unwrap().info.gamma, None);
+
+        let corrected = |display_gamma| {
+            decode_with_transformations(png.as_slice(), Transformations::new().gamma(display_gamma))
+        };
+        assert_eq!(corrected(180000).pixels, image.pixels);
+        let brighter = corrected(220000);
+        assert_eq!(brighter.color_type, K8);
+        assert_eq!(*brighter.pixels.get(0), 0);
+        assert_eq!(*brighter.pixels.get(255), 255);
+        for (i, &k) in brighter.pixels.iter().enumerate() {
+            assert!(k as uint >= i);
+        }
+        assert!(*brighter.pixels.get(128) > 128);
+
+        // 16-bit samples have a table of their own.
+        let image = Image::new(2, 1, K16, vec![0x80, 0x00, 0xff, 0xff]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let png = with_chunk(png.as_slice(), "gAMA", [0, 0, 0xd9, 0x03]);
+        let brighter = decode_with_transformations(png.as_slice(), Transformations::new().gamma(220000));
+        assert_eq!(brighter.color_type, K16);
+        let samples = brighter.pixels_u16().unwrap();
+        assert!(*samples.get(0) > 0x8000);
+        assert_eq!(*samples.get(1), 0xffff);
+    }
+
+    #[test]
+    fn test_color_info() {
+        let image = Image::new(4, 4, RGB8, noise(4 * 4 * 3));
+        let plain = encode_png_to_memory(&image).unwrap();
+        assert_eq!(load_png_from_memory(plain.as_slice()).unwrap().color_info, ColorInfo::new());
+
+        let profile = noise(1000);
+        let mut stream = DeflateStream::to_zlib(6);
+        let mut iccp = Vec::from_slice("Test profile\0\0".as_bytes());
+        iccp.push_all(stream.update(profile.as_slice()));
+        iccp.push_all(stream.finish());
+        let chrm = be_u32s([31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000]);
+        let png = with_chunk(plain.as_slice(), "sRGB", [1]);
+        let png = with_chunk(png.as_slice(), "cHRM", chrm.as_slice());
+        let png = with_chunk(png.as_slice(), "iCCP", iccp.as_slice());
+        let png = with_chunk(png.as_slice(), "cICP", [1, 13, 0, 1]);
+
+        let color = load_png_from_memory(png.as_slice()).unwrap().color_info;
+        assert_eq!(color.srgb_intent, Some(1));
+        assert_eq!(color.chromaticities, Some(Chromaticities {
+            white: (31270, 32900),
+            red: (64000, 33000),
+            green: (30000, 60000),
+            blue: (15000, 6000)
+        }));
+        let icc = color.icc_profile.as_ref().unwrap();
+        assert_eq!(icc.name.as_slice(), "Test profile");
+        assert!(icc.data == profile);
+        assert_eq!(color.cicp, Some(Cicp { primaries: 1, transfer: 13, matrix: 0, full_range: true }));
+
+        // Broken colorimetry chunks are ignored: without its Adler-32 the
+        // profile is incomplete, the name can't be empty, only compression
+        // method 0 exists and sRGB has a single byte.
+        let ignored = |name, data: &[u8]| {
+            let png = with_chunk(plain.as_slice(), name, data);
+            load_png_from_memory(png.as_slice()).unwrap().color_info
+        };
+        assert_eq!(ignored("iCCP", iccp.slice_to(iccp.len() - 4)).icc_profile, None);
+        assert_eq!(ignored("iCCP", iccp.slice_from(12)).icc_profile, None);
+        let mut unknown_method = iccp.clone();
+        *unknown_method.get_mut(13) = 1;
+        assert_eq!(ignored("iCCP", unknown_method.as_slice()).icc_profile, None);
+        assert_eq!(ignored("sRGB", [1, 0]), ColorInfo::new());
+    }
+
+    #[test]
+    fn test_to_srgb() {
+        let to_srgb = |png: &[u8]| decode_with_transformations(png, Transformations::new().to_srgb());
+
+        // Untagged images are taken to be sRGB already.
+        let image = Image::new(16, 16, RGB8, noise(16 * 16 * 3));
+        let png = encode_png_to_memory(&image).unwrap();
+        let srgb = to_srgb(png.as_slice());
+        assert_eq!(srgb.color_type, RGBA8);
+        assert_eq!(srgb.pixels, load_png_from_memory(png.as_slice()).unwrap().pixels);
+
+        // Gray, red, white and black.
+        let image = Image::new(4, 1, RGB8, vec![128, 128, 128, 255, 0, 0, 255, 255, 255, 0, 0, 0]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let expanded = load_png_from_memory(png.as_slice()).unwrap().pixels;
+
+        // Linear samples, from gAMA alone.
+        let linear = with_chunk(png.as_slice(), "gAMA", be_u32s([100000]).as_slice());
+        assert_eq!(to_srgb(linear.as_slice()).pixels,
+                   vec![188, 188, 188, 255, 255, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 255]);
+        // Which sRGB overrides.
+        let srgb = with_chunk(linear.as_slice(), "sRGB", [0]);
+        assert_eq!(to_srgb(srgb.as_slice()).pixels, expanded);
+
+        // BT.2020 primaries: grays don't change, and red is clipped to the sRGB gamut.
+        let chrm = be_u32s([31270, 32900, 70800, 29200, 17000, 79700, 13100, 4600]);
+        let wide = to_srgb(with_chunk(png.as_slice(), "cHRM", chrm.as_slice()).as_slice());
+        assert!(close(wide.pixels.as_slice(), expanded.as_slice()));
+
+        // A D50 white point is adapted to D65, so grays still don't change.
+        let chrm = be_u32s([34570, 35850, 64000, 33000, 30000, 60000, 15000, 6000]);
+        let d50 = to_srgb(with_chunk(png.as_slice(), "cHRM", chrm.as_slice()).as_slice());
+        assert!(close(d50.pixels.slice_to(4), expanded.slice_to(4)));
+        assert!(close(d50.pixels.slice(8, 16), expanded.slice(8, 16)));
+
+        // 16-bit samples are converted to 8 bits.
+        let image = Image::new(1, 1, RGB16, vec![0xff, 0xff, 0x80, 0x00, 0x00, 0x00]);
+        let png = encode_png_to_memory(&image).unwrap();
+        let srgb = to_srgb(png.as_slice());
+        assert_eq!(srgb.color_type, RGBA8);
+        assert_eq!(srgb.pixels, vec![0xff, 0x80, 0x00, 0xff]);
+    }
+
+    // A minimal ICC profile for the given color space and tags.
+    fn icc_profile(space: &str, tags: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut header = Vec::from_elem(128, 0u8);
+        header.as_mut_slice()[8] = 4;
+        for &(i, signature) in [(12u, "mntr"), (16, space), (20, "XYZ "), (36, "acsp")].iter() {
+            header.mut_slice(i, i + 4).copy_from(signature.as_bytes());
+        }
+        // The D50 illuminant of the PCS.
+        header.mut_slice(68, 80).copy_from(be_u32s([0xf6d6, 0x10000, 0xd32d]).as_slice());
+        let mut table = be_u32s([tags.len() as u32]);
+        let mut data = Vec::new();
+        for &(signature, ref tag) in tags.iter() {
+            let offset = 128 + 4 + tags.len() * 12 + data.len();
+            table.push_all(signature.as_bytes());
+            table.push_all(be_u32s([offset as u32, tag.len() as u32]).as_slice());
+            data.push_all(tag.as_slice());
+        }
+        let mut profile = header;
+        profile.push_all(table.as_slice());
+        profile.push_all(data.as_slice());
+        let size = be_u32s([profile.len() as u32]);
+        profile.mut_slice_to(4).copy_from(size.as_slice());
+        profile
+    }
+
+    fn with_iccp(png: &[u8], profile: &[u8]) -> Vec<u8> {
+        let mut stream = DeflateStream::to_zlib(6);
+        let mut iccp = Vec::from_slice("Test profile\0\0".as_bytes());
+        iccp.push_all(stream.update(profile));
+        iccp.push_all(stream.finish());
+        with_chunk(png, "iCCP", iccp.as_slice())
+    }
+
+    #[test]
+    fn test_icc_profiles() {
+        fn fixed(v: f64) -> u32 {
+            (v * 65536.0 + 0.5) as u32
+        }
+        fn xyz(x: f64, y: f64, z: f64) -> Vec<u8> {
+            let mut tag = Vec::from_slice("XYZ \0\0\0\0".as_bytes());
+            tag.push_all(be_u32s([fixed(x), fixed(y), fixed(z)]).as_slice());
+            tag
+        }
+        // sRGB colorants, adapted to D50.
+        fn rgb_profile(trc: &Vec<u8>) -> Vec<u8> {
+            icc_profile("RGB ", [
+                ("rXYZ", xyz(0.4361, 0.2225, 0.0139)),
+                ("gXYZ", xyz(0.3851, 0.7169, 0.0971)),
+                ("bXYZ", xyz(0.1431, 0.0606, 0.7141)),
+                ("rTRC", trc.clone()),
+                ("gTRC", trc.clone()),
+                ("bTRC", trc.clone())
+            ])
+        }
+        let linear = Vec::from_slice("curv\0\0\0\0\0\0\0\0".as_bytes());
+        let mut srgb = Vec::from_slice("para\0\0\0\0\0\x03\0\0".as_bytes());
+        srgb.push_all(be_u32s([fixed(2.4), fixed(1.0 / 1.055), fixed(0.055 / 1.055),
+                               fixed(1.0 / 12.92), fixed(0.04045)]).as_slice());
+        let to_srgb = |png: &[u8]| decode_with_transformations(png, Transformations::new().to_srgb());
+
+        let image = Image::new(16, 16, RGB8, noise(16 * 16 * 3));
+        let png = encode_png_to_memory(&image).unwrap();
+        let expanded = load_png_from_memory(png.as_slice()).unwrap().pixels;
+
+        // An sRGB profile doesn't change the colors.
+        let profile = rgb_profile(&srgb);
+        let icc = IccProfile { name: "sRGB".to_string(), data: profile.clone() };
+        assert!(icc.check().is_ok());
+        let decoded = to_srgb(with_iccp(png.as_slice(), profile.as_slice()).as_slice());
+        assert!(close(decoded.pixels.as_slice(), expanded.as_slice()));
+
+        // Linear samples, which an sRGB chunk overrides.
+        let png_linear = with_iccp(png.as_slice(), rgb_profile(&linear).as_slice());
+        let decoded = to_srgb(png_linear.as_slice());
+        assert!(!close(decoded.pixels.as_slice(), expanded.as_slice()));
+        let decoded = to_srgb(with_chunk(png_linear.as_slice(), "sRGB", [0]).as_slice());
+        assert_eq!(decoded.pixels, expanded);
+
+        // A gray profile, with a gamma of 1.0.
+        let gamma = Vec::from_slice("curv\0\0\0\0\0\0\0\x01\x01\0".as_bytes());
+        let gray_profile = icc_profile("GRAY", [("kTRC", gamma)]);
+        let gray = Image::new(2, 1, K8, vec![128, 255]);
+        let gray_png = encode_png_to_memory(&gray).unwrap();
+        let decoded = to_srgb(with_iccp(gray_png.as_slice(), gray_profile.as_slice()).as_slice());
+        assert_eq!(decoded.pixels, vec![188, 188, 188, 255, 255, 255, 255, 255]);
+        // But not for RGB images.
+        let decoded = to_srgb(with_iccp(png.as_slice(), gray_profile.as_slice()).as_slice());
+        assert_eq!(decoded.pixels, expanded);
+
+        // Profiles based on lookup tables are unsupported, and ignored.
+        let lut_profile = icc_profile("RGB ", [("A2B0", Vec::from_slice("mft2\0\0\0\0".as_bytes()))]);
+        let icc = IccProfile { name: "LUT".to_string(), data: lut_profile.clone() };
+        match icc.check() {
+            Err(Unsupported(_)) => {}
+            r => fail!("unexpected result {}", r)
+        }
+        let decoded = to_srgb(with_iccp(png.as_slice(), lut_profile.as_slice()).as_slice());
+        assert_eq!(decoded.pixels, expanded);
+
+        // Broken profiles, which are ignored: noise, a PCS illuminant of 0,
+        // which the colors can't be adapted from, and colorants that are all
+        // the same, which can't be inverted.
+        let mut black = rgb_profile(&srgb);
+        black.mut_slice(68, 80).copy_from([0u8, ..12]);
+        let gray_colorants = icc_profile("RGB ", [
+            ("rXYZ", xyz(0.3, 0.3, 0.3)),
+            ("gXYZ", xyz(0.3, 0.3, 0.3)),
+            ("bXYZ", xyz(0.3, 0.3, 0.3)),
+            ("rTRC", srgb.clone()),
+            ("gTRC", srgb.clone()),
+            ("bTRC", srgb.clone())
+        ]);
+        for profile in [noise(1000), black, gray_colorants].iter() {
+            let icc = IccProfile { name: "Broken".to_string(), data: profile.clone() };
+            match icc.check() {
+                Err(Malformed(_)) => {}
+                r => fail!("unexpected result {}", r)
+            }
+            let decoded = to_srgb(with_iccp(png.as_slice(), profile.as_slice()).as_slice());
+            assert_eq!(decoded.pixels, expanded);
+        }
+    }
+
+    #[test]
+    fn test_crc_mismatch() {
+        let image = Image::new(4, 4, KA8, Vec::from_elem(4 * 4 * 2, 0x80u8));
+        let mut png = encode_png_to_memory(&image).unwrap();
+        // Corrupt the CRC of IHDR, right after the signature and 8 + 13 bytes of chunk.
+        *png.get_mut(8 + 8 + 13) ^= 0xff;
+
+        match load_png_from_memory(png.as_slice()) {
+            Err(e) => {
+                match e.error {
+                    CrcMismatch(name, _, _) => assert!(name.as_slice() == "IHDR".as_bytes()),
+                    _ => fail!("unexpected error {}", e)
+                }
+                // The error is found at the last byte of the CRC.
+                assert_eq!(e.offset, 8 + 8 + 13 + 3);
+                assert!(e.chunk == Some((['I' as u8, 'H' as u8, 'D' as u8, 'R' as u8], 0)));
+                assert!(e.scanline.is_none());
+            }
+            Ok(_) => fail!("corrupted CRC wasn't detected")
+        }
+
+        let mut decoder = Some(box Decoder::with_options(DecoderOptions {
+            crc_mode: CrcIgnore,
+            ..DecoderOptions::new()
+        }));
+        match decoder.update(png.as_slice()) {
+            Complete(decoded) => assert_eq!(decoded.pixels, image.pixels),
+            Partial(_) => fail!("incomplete PNG file"),
+            Error(e) => fail!("{}", e)
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let image = Image::new(67, 45, RGBA8, noise(67 * 45 * 4));
+        let png = encode_png_to_memory(&image).unwrap();
+        assert!(is_png(png.as_slice()));
+        assert!(!is_png(png.slice_to(7)));
+
+        let info = probe(png.as_slice()).unwrap();
+        assert_eq!(info, image.info);
+
+        // IHDR ends after the signature and 8 + 13 + 4 bytes of chunk.
+        let mut incremental = Probe::new();
+        for (i, b) in png.slice_to(8 + 8 + 13 + 4).iter().enumerate() {
+            match incremental.update([*b]) {
+                Ok(None) => assert!(i < 8 + 8 + 13 + 3),
+                Ok(Some(found)) => assert_eq!(found, info),
+                Err(e) => fail!("{}", e)
+            }
+        }
+
+        match probe(png.slice_to(20)) {
+            Err(e) => assert!(e.error == Truncated),
+            Ok(_) => fail!("truncated IHDR wasn't detected")
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let image = Image::new(67, 45, RGBA8, noise(67 * 45 * 4));
+        let png = encode_png_to_memory(&image).unwrap();
+
+        let decode = |limits: DecoderLimits| {
+            let mut decoder = Some(box Decoder::with_options(DecoderOptions {
+                limits: limits,
+                ..DecoderOptions::new()
+            }));
+            match decoder.update(png.as_slice()) {
+                Complete(_) => None,
+                Partial(_) => fail!("incomplete PNG file"),
+                Error(e) => Some(e.error)
+            }
+        };
+        let exceeded = |limits: DecoderLimits| {
+            match decode(limits) {
+                Some(LimitExceeded(_)) => {}
+                Some(e) => fail!("unexpected error {}", e),
+                None => fail!("limit wasn't enforced")
+            }
+        };
+
+        assert!(decode(DecoderLimits::new()).is_none());
+        exceeded(DecoderLimits { max_width: 66, ..DecoderLimits::new() });
+        exceeded(DecoderLimits { max_height: 44, ..DecoderLimits::new() });
+        exceeded(DecoderLimits { max_pixel_bytes: 67 * 45 * 4 - 1, ..DecoderLimits::new() });
+        exceeded(DecoderLimits { max_inflated_bytes: 1000, ..DecoderLimits::new() });
+        exceeded(DecoderLimits { max_chunks: 2, ..DecoderLimits::new() });
+        assert!(decode(DecoderLimits { max_pixel_bytes: 67 * 45 * 4, ..DecoderLimits::new() }).is_none());
+    }
+
+    // A 4x4 KA8 image with all its samples at 0x80, and its PNG file, which
+    // only has IHDR, a single IDAT and IEND.
+    fn encoded_fixture() -> (Image, Vec<u8>) {
+        let image = Image::new(4, 4, KA8, Vec::from_elem(4 * 4 * 2, 0x80u8));
+        let png = encode_png_to_memory(&image).unwrap();
+        (image, png)
+    }
+
+    // For PNG files edited without updating the CRCs.
+    fn decode_ignoring_crc(png: &[u8]) -> Result<Image, DecodeError> {
+        let mut decoder = Some(box Decoder::with_options(DecoderOptions {
+            crc_mode: CrcIgnore,
+            ..DecoderOptions::new()
+        }));
+        match decoder.update(png) {
+            Partial(_) => fail!("incomplete PNG file"),
+            Complete(image) => Ok(image),
+            Error(e) => Err(e)
+        }
+    }
+
+    #[test]
+    fn test_empty_image() {
+        let (_, mut png) = encoded_fixture();
+        // Zero the width.
+        for b in png.mut_slice(8 + 8, 8 + 8 + 4).mut_iter() {
+            *b = 0;
+        }
+        match decode_ignoring_crc(png.as_slice()) {
+            Err(e) => match e.error {
+                Malformed(_) => {}
+                _ => fail!("unexpected error {}", e)
+            },
+            Ok(_) => fail!("0x4 image wasn't rejected")
+        }
+    }
+
+    #[test]
+    fn test_idat_error_context() {
+        let (image, png) = encoded_fixture();
+        // Corrupt the Adler-32 at the end of IDAT, before its CRC and IEND.
+        let mut corrupt = png.clone();
+        let last = png.len() - 12 - 4 - 1;
+        *corrupt.get_mut(last) ^= 0xff;
+        match decode_ignoring_crc(corrupt.as_slice()) {
+            Err(e) => {
+                match e.error {
+                    Inflate(Adler32Mismatch(_, _)) => {}
+                    _ => fail!("unexpected error {}", e)
+                }
+                assert!(e.chunk == Some((['I' as u8, 'D' as u8, 'A' as u8, 'T' as u8], 1)));
+                // All the scanlines were decoded before the trailer.
+                assert!(e.scanline.is_none());
+                assert_eq!(e.offset, last as u64);
+            }
+            Ok(_) => fail!("corrupted Adler-32 wasn't detected")
+        }
+
+        // An unknown filter type on the third scanline, in a stored block.
+        let mut raw = Vec::new();
+        for (y, row) in image.pixels.as_slice().chunks(4 * 2).enumerate() {
+            raw.push(if y == 2 { 9u8 } else { 0 });
+            raw.push_all(row);
+        }
+        let mut stream = DeflateStream::to_zlib(0);
+        let mut idat = Vec::from_slice(stream.update(raw.as_slice()));
+        idat.push_all(stream.finish());
+        let mut png = Vec::from_slice(png.slice_to(8 + 25));
+        png.push_all(chunk("IDAT", idat.as_slice()).as_slice());
+        png.push_all(chunk("IEND", []).as_slice());
+
+        // Byte by byte, so that the error is found as soon as the filter type is.
+        let mut decoder = Some(box Decoder::new());
+        for &b in png.iter() {
+            match decoder.update([b]) {
+                Partial(_) => {}
+                Error(e) => {
+                    match e.error {
+                        Malformed(_) => {}
+                        _ => fail!("unexpected error {}", e)
+                    }
+                    assert!(e.scanline == Some((2, 0)));
+                    // After the IDAT chunk header, the ZLIB header and the stored block header.
+                    assert_eq!(e.offset, (8 + 25 + 8 + 2 + 5 + 2 * 9) as u64);
+                    return;
+                }
+                Complete(_) => break
+            }
+        }
+        fail!("unknown filter type wasn't detected");
+    }
+
+    #[test]
+    fn test_truncated_idat() {
+        let (image, png) = encoded_fixture();
+        // The data of the only IDAT chunk, which is followed by IEND.
+        let idat = png.slice(8 + 25 + 8, png.len() - 12 - 4);
+        let truncated = |idat: Option<&[u8]>| {
+            let mut png = Vec::from_slice(png.slice_to(8 + 25));
+            match idat {
+                Some(data) => png.push_all(chunk("IDAT", data).as_slice()),
+                None => {}
+            }
+            png.push_all(chunk("IEND", []).as_slice());
+            match load_png_from_memory(png.as_slice()) {
+                Err(e) => assert!(e.error == Truncated),
+                Ok(_) => fail!("incomplete IDAT data wasn't detected")
+            }
+        };
+        truncated(None);
+        truncated(Some(idat.slice_to(idat.len() / 2)));
+        // Without the Adler-32, the scanlines can't be checked.
+        truncated(Some(idat.slice_to(idat.len() - 4)));
+        assert_eq!(load_png_from_memory(png.as_slice()).unwrap().pixels, image.pixels);
+    }
+
     fn load_rgba8_in_chunks(file: &'static str, chunk_size: uint, w: u32, h: u32) {
         spawn(proc() {
             let mut reader = match File::open_mode(&Path::new(file), io::Open, io::Read) {
@@ -1132,7 +3800,7 @@ mod test {
                             assert_eq!(image.height, h);
                             break;
                         },
-                        Error(m) => fail!(m)
+                        Error(e) => fail!("{}", e)
                     },
                     Err(m) => fail!("incomplete PNG file {}", m)
                 }
@@ -1156,7 +3824,7 @@ mod test {
         let buf = reader.read_to_end().ok().unwrap();
         let bs = bench::benchmark(|b| b.iter(|| {
             match load_png_from_memory(buf) {
-                Err(m) => fail!(m),
+                Err(e) => fail!("{}", e),
                 Ok(image) => {
                     assert_eq!(image.color_type, c);
                     assert_eq!(image.width, w);